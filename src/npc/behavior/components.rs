@@ -1 +1,94 @@
 use bevy::prelude::*;
+
+use super::utility::Action;
+
+/// Where an NPC is doing its action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionTarget {
+    /// A slot of a piece of furniture
+    Furniture { entity: Entity, slot: usize },
+    /// A tile of a room
+    Room { id: u32, tile: (i32, i32) },
+}
+
+/// Action chosen by the utility AI and how it is going.
+///
+/// The NPC first walks to the target and then spends the duration of the action there.
+#[derive(Component, Default)]
+pub struct Brain {
+    pub action: Option<Action>,
+    pub target: Option<ActionTarget>,
+    /// Seconds left doing the action, counted once the NPC arrives
+    pub remaining: f32,
+    /// Whether the last action was given up because its target could not be reached
+    pub failed: bool,
+    /// Targets that could not be reached and the seconds left before trying them again
+    pub blocked: Vec<(ActionTarget, f32)>,
+}
+
+impl Brain {
+    pub fn clear(&mut self) {
+        self.action = None;
+        self.target = None;
+        self.remaining = 0.0;
+    }
+
+    /// Stop choosing a target for some seconds of game time
+    pub fn block(&mut self, target: ActionTarget, seconds: f32) {
+        self.blocked.push((target, seconds));
+    }
+
+    /// Count down the blocked targets, trying them again once their time runs out
+    pub fn cool_down(&mut self, seconds: f32) {
+        for (_, left) in &mut self.blocked {
+            *left -= seconds;
+        }
+        self.blocked.retain(|(_, left)| *left > 0.0);
+    }
+
+    /// Return if a target is blocked, the whole piece of furniture or room is blocked and not only the slot or tile
+    pub fn is_blocked(&self, target: &ActionTarget) -> bool {
+        self.blocked
+            .iter()
+            .any(|(blocked, _)| match (blocked, target) {
+                (
+                    ActionTarget::Furniture { entity: a, .. },
+                    ActionTarget::Furniture { entity: b, .. },
+                ) => a == b,
+                (ActionTarget::Room { id: a, .. }, ActionTarget::Room { id: b, .. }) => a == b,
+                _ => false,
+            })
+    }
+}
+
+/// Where an NPC is in its behavior tree.
+#[derive(Component)]
+pub struct BehaviorTreeState {
+    /// Name of the tree in [BehaviorTrees](super::resources::BehaviorTrees)
+    pub tree: String,
+    pub checked_in: bool,
+    /// Whether the NPC ate its lunch today
+    pub had_lunch: bool,
+    /// Path of child indices to the running action
+    pub running: Vec<usize>,
+    /// Place the running go to action walks to, only the arrivals there count
+    pub goal: Option<Vec3>,
+    /// Whether the NPC reached the goal, once it knows
+    pub arrival: Option<bool>,
+    /// Seconds left of the running wait action
+    pub wait: f32,
+}
+
+impl BehaviorTreeState {
+    pub fn new(tree: &str) -> Self {
+        Self {
+            tree: tree.to_string(),
+            checked_in: false,
+            had_lunch: false,
+            running: vec![],
+            goal: None,
+            arrival: None,
+            wait: 0.0,
+        }
+    }
+}
@@ -8,11 +8,29 @@ pub mod resources;
 use resources::*;
 pub mod states;
 use states::*;
+pub mod utility;
 
 pub struct BehaviorPlugin;
 
 impl Plugin for BehaviorPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, handle_walking);
+        app.init_resource::<GameClock>()
+            .init_resource::<BehaviorTrees>();
+
+        app.add_systems(Startup, load_behavior_trees);
+        app.add_systems(Update, advance_clock);
+        app.add_systems(
+            Update,
+            (
+                handle_walking,
+                handle_path_events,
+                perform_actions,
+                record_tree_arrivals,
+                run_behavior_trees,
+                choose_actions,
+            )
+                .chain()
+                .after(advance_clock),
+        );
     }
 }
@@ -1 +1,345 @@
 use bevy::prelude::*;
+use hashbrown::HashMap;
+use serde::Deserialize;
+use std::fs::File;
+
+use crate::npc::components::Need;
+
+use super::utility::Action;
+
+/// Seconds in a game day
+const DAY: f32 = 24.0 * 60.0 * 60.0;
+
+/// Time of the day in the game.
+#[derive(Resource)]
+pub struct GameClock {
+    /// Seconds since midnight
+    pub seconds: f32,
+    /// Seconds of game time that passed in the last frame, every simulation advances by it
+    pub delta: f32,
+    /// Seconds of game time that pass in each real second
+    pub time_scale: f32,
+}
+
+impl Default for GameClock {
+    fn default() -> Self {
+        Self {
+            seconds: 8.0 * 60.0 * 60.0,
+            delta: 0.0,
+            time_scale: 60.0,
+        }
+    }
+}
+
+impl GameClock {
+    pub fn advance(&mut self, real_seconds: f32) {
+        self.delta = real_seconds * self.time_scale;
+        self.seconds = (self.seconds + self.delta).rem_euclid(DAY);
+    }
+
+    /// Hour of the day, from 0 to 24
+    pub fn hour(&self) -> f32 {
+        self.seconds / 3600.0
+    }
+}
+
+/// Routines of the NPCs by name, loaded from `config/behavior_trees.json`.
+#[derive(Resource, Default, Deserialize)]
+pub struct BehaviorTrees {
+    trees: HashMap<String, BehaviorNode>,
+}
+
+impl BehaviorTrees {
+    /// Load the json file of the behavior trees
+    pub fn load(path: &str) -> Self {
+        let file = File::open(path).expect("Failed to open behavior trees");
+        serde_json::from_reader(file).expect("Error while reading json")
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BehaviorNode> {
+        self.trees.get(name)
+    }
+}
+
+/// Result of ticking a node of a behavior tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeStatus {
+    Success,
+    Failure,
+    Running,
+}
+
+/// Question a condition node asks about the NPC or the time of the day.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeCondition {
+    /// The hour of the day is this one or later
+    HourAfter(f32),
+    /// The hour of the day is earlier than this one
+    HourBefore(f32),
+    /// Whether the NPC checked in today
+    CheckedIn(bool),
+    /// Whether the NPC ate its lunch today
+    HadLunch(bool),
+    /// A need of the NPC is below a value
+    NeedBelow { need: Need, value: u32 },
+}
+
+/// Rust action an action node runs.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeAction {
+    /// Walk to a position of the floor, given as x and z
+    GoTo([f32; 2]),
+    /// Mark the NPC as arrived for the day
+    CheckIn,
+    /// Mark the NPC as gone for the day, its lunch is for the next day
+    CheckOut,
+    /// Mark the lunch of the NPC as eaten
+    FinishLunch,
+    /// Do an action at the nearest free place for it
+    Perform(Action),
+    /// Let the utility AI choose the action to do
+    Decide,
+    /// Stand still for some seconds
+    Wait(f32),
+}
+
+/// Node of a behavior tree.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BehaviorNode {
+    /// Run the children in order until one does not succeed
+    Sequence {
+        children: Vec<BehaviorNode>,
+    },
+    /// Run the children in order until one does not fail
+    Selector {
+        children: Vec<BehaviorNode>,
+    },
+    Condition {
+        condition: TreeCondition,
+    },
+    Action {
+        action: TreeAction,
+    },
+}
+
+impl BehaviorNode {
+    /// Tick the node and return its status.
+    ///
+    /// `running` is the path of child indices to the action that was left running in the last tick, the
+    /// tick resumes from it and leaves there the path to the action that is running now, or nothing when
+    /// the node finished. `act` gets whether it resumes the action instead of starting it.
+    ///
+    /// Selectors check their higher priority children before resuming, one whose leading conditions pass is
+    /// ticked and takes over unless it fails.
+    pub fn tick(
+        &self,
+        running: &mut Vec<usize>,
+        check: &mut impl FnMut(&TreeCondition) -> bool,
+        act: &mut impl FnMut(&TreeAction, bool) -> TreeStatus,
+    ) -> TreeStatus {
+        let resume = std::mem::take(running);
+        let status = self.tick_from(&resume, !resume.is_empty(), running, check, act);
+        if status != TreeStatus::Running {
+            running.clear();
+        }
+        status
+    }
+
+    fn tick_from(
+        &self,
+        resume: &[usize],
+        resuming: bool,
+        running: &mut Vec<usize>,
+        check: &mut impl FnMut(&TreeCondition) -> bool,
+        act: &mut impl FnMut(&TreeAction, bool) -> TreeStatus,
+    ) -> TreeStatus {
+        let (children, stop_on) = match self {
+            BehaviorNode::Condition { condition } => {
+                return if check(condition) {
+                    TreeStatus::Success
+                } else {
+                    TreeStatus::Failure
+                };
+            }
+            BehaviorNode::Action { action } => return act(action, resuming),
+            BehaviorNode::Sequence { children } => (children, TreeStatus::Failure),
+            BehaviorNode::Selector { children } => (children, TreeStatus::Success),
+        };
+
+        let (start, rest) = match resume.split_first() {
+            Some((&start, rest)) if resuming => (start, rest),
+            _ => (0, &[][..]),
+        };
+        if stop_on == TreeStatus::Success {
+            for (index, child) in children.iter().enumerate().take(start) {
+                if !child.guard_passes(check) {
+                    continue;
+                }
+                running.push(index);
+                let status = child.tick_from(&[], false, running, check, act);
+                if status == TreeStatus::Running {
+                    return status;
+                }
+                running.pop();
+                if status == TreeStatus::Success {
+                    return status;
+                }
+            }
+        }
+        for (index, child) in children.iter().enumerate().skip(start) {
+            let resuming = resuming && index == start;
+            running.push(index);
+            let status = child.tick_from(
+                if resuming { rest } else { &[] },
+                resuming,
+                running,
+                check,
+                act,
+            );
+            if status == TreeStatus::Running {
+                return status;
+            }
+            running.pop();
+            if status == stop_on {
+                return status;
+            }
+        }
+
+        match stop_on {
+            TreeStatus::Failure => TreeStatus::Success,
+            _ => TreeStatus::Failure,
+        }
+    }
+
+    /// Return if the node starts with conditions and all of them pass
+    fn guard_passes(&self, check: &mut impl FnMut(&TreeCondition) -> bool) -> bool {
+        let conditions: Vec<&TreeCondition> = match self {
+            BehaviorNode::Condition { condition } => vec![condition],
+            BehaviorNode::Sequence { children } => children
+                .iter()
+                .map_while(|child| match child {
+                    BehaviorNode::Condition { condition } => Some(condition),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        !conditions.is_empty() && conditions.into_iter().all(check)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(action: TreeAction) -> BehaviorNode {
+        BehaviorNode::Action { action }
+    }
+
+    #[test]
+    fn config_trees_are_valid() {
+        let trees = BehaviorTrees::load("./config/behavior_trees.json");
+
+        assert!(trees.get("office_day").is_some());
+    }
+
+    #[test]
+    fn running_actions_resume_where_they_were() {
+        let tree = BehaviorNode::Selector {
+            children: vec![
+                BehaviorNode::Sequence {
+                    children: vec![
+                        BehaviorNode::Condition {
+                            condition: TreeCondition::CheckedIn(false),
+                        },
+                        action(TreeAction::CheckIn),
+                    ],
+                },
+                BehaviorNode::Sequence {
+                    children: vec![action(TreeAction::Wait(1.0)), action(TreeAction::Decide)],
+                },
+            ],
+        };
+        let mut started = vec![];
+        let mut running = vec![];
+
+        // the wait starts and keeps running in the second tick, then the sequence goes on to decide
+        for tick in 0..3 {
+            let checked_in = tick < 2;
+            let status = tree.tick(
+                &mut running,
+                &mut |condition| *condition == TreeCondition::CheckedIn(checked_in),
+                &mut |action, resuming| {
+                    if !resuming {
+                        started.push(*action);
+                    }
+                    match (action, resuming) {
+                        (TreeAction::Wait(_), false) => TreeStatus::Running,
+                        _ => TreeStatus::Success,
+                    }
+                },
+            );
+            if running.is_empty() {
+                assert_eq!(status, TreeStatus::Success);
+            }
+        }
+
+        assert_eq!(
+            started,
+            vec![
+                TreeAction::Wait(1.0),
+                TreeAction::Decide,
+                TreeAction::CheckIn
+            ]
+        );
+    }
+
+    #[test]
+    fn higher_priority_branches_take_over_running_actions() {
+        let tree = BehaviorNode::Selector {
+            children: vec![
+                BehaviorNode::Sequence {
+                    children: vec![
+                        BehaviorNode::Condition {
+                            condition: TreeCondition::HourAfter(12.0),
+                        },
+                        action(TreeAction::Perform(Action::Eat)),
+                    ],
+                },
+                action(TreeAction::Perform(Action::Work)),
+            ],
+        };
+        let mut running = vec![];
+        let mut tick = |hour: f32, running: &mut Vec<usize>| {
+            let mut started = vec![];
+            tree.tick(
+                running,
+                &mut |condition| match *condition {
+                    TreeCondition::HourAfter(after) => hour >= after,
+                    _ => false,
+                },
+                &mut |action, resuming| {
+                    if !resuming {
+                        started.push(*action);
+                    }
+                    TreeStatus::Running
+                },
+            );
+            started
+        };
+
+        assert_eq!(
+            tick(11.0, &mut running),
+            vec![TreeAction::Perform(Action::Work)]
+        );
+        assert!(tick(11.5, &mut running).is_empty());
+        assert_eq!(
+            tick(12.0, &mut running),
+            vec![TreeAction::Perform(Action::Eat)]
+        );
+        assert_eq!(running, vec![0, 1]);
+    }
+}
@@ -1,6 +1,6 @@
 use bevy::prelude::*;
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum BehaviorState {
     Walking,
     Waiting,
@@ -1,44 +1,1253 @@
-use bevy::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*};
 
-use crate::npc::{components::Npc, pathfinding::components::Pathfinding};
+use crate::{
+    npc::{
+        components::{Need, Npc},
+        pathfinding::{
+            components::Pathfinding,
+            events::{DestinationReached, PathFailed, PathStarted, WaypointReached},
+            resources::{FlowFields, PathPriority, PathfindingQueue},
+        },
+    },
+    world::{
+        furniture::components::Furniture,
+        grid::resources::{Grid, PathRequest, PathfindingError},
+        rooms::resources::Rooms,
+    },
+};
 
-use super::states::BehaviorState;
+use super::{
+    components::{ActionTarget, BehaviorTreeState, Brain},
+    resources::{BehaviorTrees, GameClock, TreeAction, TreeCondition, TreeStatus},
+    states::BehaviorState,
+    utility::{rank_actions, Action, ActionPlace, UtilityInput},
+};
 
-/// Handles the path following for each NPC
+/// Walk the NPCs that got a path and stop them when their path fails
+pub fn handle_path_events(
+    mut npcs: Query<&mut Npc>,
+    mut path_started: EventReader<PathStarted>,
+    mut path_failed: EventReader<PathFailed>,
+) {
+    for event in path_started.iter() {
+        if let Ok(mut npc) = npcs.get_mut(event.entity) {
+            npc.behavior_state = BehaviorState::Walking;
+        }
+    }
+
+    for event in path_failed.iter() {
+        if let Ok(mut npc) = npcs.get_mut(event.entity) {
+            if matches!(
+                npc.behavior_state,
+                BehaviorState::Walking | BehaviorState::Waiting
+            ) {
+                npc.behavior_state = BehaviorState::Idle;
+            }
+        }
+    }
+}
+
+/// Load the routines of the NPCs
+pub fn load_behavior_trees(mut trees: ResMut<BehaviorTrees>) {
+    *trees = BehaviorTrees::load("./config/behavior_trees.json");
+}
+
+/// Advance the time of the day
+pub fn advance_clock(time: Res<Time>, mut clock: ResMut<GameClock>) {
+    clock.advance(time.delta_seconds());
+}
+
+/// Pick the best scored action of every idle NPC that has a place to do it and send the NPC there.
+///
+/// NPCs following a behavior tree only decide when their tree asks for it.
+pub fn choose_actions(
+    clock: Res<GameClock>,
+    mut planner: ActionPlanner,
+    mut npcs: Query<
+        (Entity, &Transform, &mut Npc, &mut Brain, &mut Pathfinding),
+        Without<BehaviorTreeState>,
+    >,
+) {
+    for (entity, transform, mut npc, mut brain, mut pathfinding) in &mut npcs {
+        if npc.behavior_state != BehaviorState::Idle || brain.action.is_some() {
+            continue;
+        }
+
+        planner.decide(
+            entity,
+            transform.translation,
+            clock.hour(),
+            &mut npc,
+            &mut brain,
+            &mut pathfinding,
+        );
+    }
+}
+
+/// Everything needed to find where an action can be done and to send an NPC there.
+#[derive(SystemParam)]
+pub struct ActionPlanner<'w, 's> {
+    grid: Res<'w, Grid>,
+    rooms: Res<'w, Rooms>,
+    queue: ResMut<'w, PathfindingQueue>,
+    furniture_query: Query<'w, 's, (Entity, &'static Transform, &'static mut Furniture)>,
+}
+
+impl ActionPlanner<'_, '_> {
+    /// Start the best scored action that has a place to do it, returning false when there is none
+    pub fn decide(
+        &mut self,
+        entity: Entity,
+        position: Vec3,
+        hour: f32,
+        npc: &mut Npc,
+        brain: &mut Brain,
+        pathfinding: &mut Pathfinding,
+    ) -> bool {
+        let input = UtilityInput {
+            needs: &npc.needs,
+            atributtes: &npc.atributtes,
+            role: npc.role,
+            hour,
+        };
+        rank_actions(&input)
+            .into_iter()
+            .take_while(|&(_, score)| score > 0.0)
+            .any(|(action, _)| self.start(action, entity, position, npc, brain, pathfinding))
+    }
+
+    /// Send the NPC to the nearest free place of an action, returning false when there is none
+    pub fn start(
+        &mut self,
+        action: Action,
+        entity: Entity,
+        position: Vec3,
+        npc: &mut Npc,
+        brain: &mut Brain,
+        pathfinding: &mut Pathfinding,
+    ) -> bool {
+        let Some((target, goal, facing)) = action
+            .places()
+            .iter()
+            .find_map(|&place| self.find(place, entity, position, brain))
+        else {
+            return false;
+        };
+
+        // the new action takes over from the one going on, which frees its place
+        if brain.action.is_some() {
+            if brain.target != Some(target) {
+                self.release(entity, brain.target);
+            }
+            pathfinding.path = None;
+        }
+        brain.action = Some(action);
+        brain.target = Some(target);
+        brain.remaining = action.duration();
+        brain.failed = false;
+        pathfinding.final_facing = facing;
+        self.walk(entity, position, goal, npc);
+        true
+    }
+
+    /// Give up the action of the NPC, freeing its slot and stopping its path
+    pub fn cancel(
+        &mut self,
+        entity: Entity,
+        npc: &mut Npc,
+        brain: &mut Brain,
+        pathfinding: &mut Pathfinding,
+    ) {
+        if brain.action.is_none() {
+            return;
+        }
+        self.release(entity, brain.target);
+        brain.clear();
+        pathfinding.path = None;
+        pathfinding.final_facing = None;
+        npc.behavior_state = BehaviorState::Idle;
+    }
+
+    /// Free the slot the NPC took when the target is a piece of furniture
+    fn release(&mut self, entity: Entity, target: Option<ActionTarget>) {
+        if let Some(ActionTarget::Furniture { entity: target, .. }) = target {
+            if let Ok((_, _, mut furniture)) = self.furniture_query.get_mut(target) {
+                furniture.release_slot(entity);
+            }
+        }
+    }
+
+    /// Queue a path for the NPC and leave it waiting for it
+    pub fn walk(&mut self, entity: Entity, position: Vec3, goal: Vec3, npc: &mut Npc) {
+        self.queue.push(
+            entity,
+            PathRequest::new(position, goal),
+            PathPriority::Normal,
+        );
+        npc.behavior_state = BehaviorState::Waiting;
+    }
+
+    /// Find the nearest free place of a kind that the NPC did not fail to reach lately, taking the slot when it
+    /// is a piece of furniture.
+    ///
+    /// Returns the target, the position to walk to and the direction to face there.
+    fn find(
+        &mut self,
+        place: ActionPlace,
+        entity: Entity,
+        position: Vec3,
+        brain: &Brain,
+    ) -> Option<(ActionTarget, Vec3, Option<Vec3>)> {
+        match place {
+            ActionPlace::Furniture(kind) => {
+                let (furniture_entity, _) = self
+                    .furniture_query
+                    .iter()
+                    .filter(|(other, _, furniture)| {
+                        furniture.kind == kind
+                            && furniture.is_available()
+                            && !brain.is_blocked(&ActionTarget::Furniture {
+                                entity: *other,
+                                slot: 0,
+                            })
+                    })
+                    .map(|(other, transform, _)| (other, transform.translation.distance(position)))
+                    .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+                let (_, transform, mut furniture) =
+                    self.furniture_query.get_mut(furniture_entity).ok()?;
+                let slot = furniture.take_slot(entity)?;
+                let (goal, facing) = furniture.slot_world(transform, slot)?;
+                Some((
+                    ActionTarget::Furniture {
+                        entity: furniture_entity,
+                        slot,
+                    },
+                    goal,
+                    Some(facing),
+                ))
+            }
+            ActionPlace::Room(room_type) => {
+                let grid = &self.grid;
+                let (id, tile, _) = self
+                    .rooms
+                    .iter()
+                    .filter(|room| room.room_type == Some(room_type) && room.is_valid())
+                    .filter(|room| {
+                        !brain.is_blocked(&ActionTarget::Room {
+                            id: room.id,
+                            tile: (0, 0),
+                        })
+                    })
+                    .flat_map(|room| room.tiles.iter().map(move |&tile| (room.id, tile)))
+                    .map(|(id, tile)| {
+                        let center = grid.coord_to_tile([tile.0, tile.1]);
+                        (id, tile, center.distance(position))
+                    })
+                    .min_by(|a, b| a.2.total_cmp(&b.2).then(a.1.cmp(&b.1)))?;
+
+                Some((
+                    ActionTarget::Room { id, tile },
+                    grid.coord_to_tile([tile.0, tile.1]),
+                    None,
+                ))
+            }
+        }
+    }
+}
+
+/// Remember whether the NPCs following a behavior tree reached the place they were walking to.
+///
+/// Only the events about the goal of the running go to action count, not the ones of the paths of actions.
+pub fn record_tree_arrivals(
+    grid: Res<Grid>,
+    mut npcs: Query<&mut BehaviorTreeState>,
+    mut destination_reached: EventReader<DestinationReached>,
+    mut path_failed: EventReader<PathFailed>,
+) {
+    let goal_tile = |state: &BehaviorTreeState| {
+        let goal = grid.world_to_coord(state.goal?);
+        Some((goal[0], goal[1]))
+    };
+
+    for event in destination_reached.iter() {
+        if let Ok(mut state) = npcs.get_mut(event.entity) {
+            // the path may end on the tile next to the goal when the goal lies on a tile border
+            let tile = grid.world_to_coord(event.position);
+            if goal_tile(&state)
+                .is_some_and(|goal| (goal.0 - tile[0]).abs() <= 1 && (goal.1 - tile[1]).abs() <= 1)
+            {
+                state.arrival = Some(true);
+            }
+        }
+    }
+    for event in path_failed.iter() {
+        if let Ok(mut state) = npcs.get_mut(event.entity) {
+            // errors that do not know the goal are about whatever the NPC was walking to
+            let goal = goal_tile(&state);
+            if goal.is_some() && event.error.goal().is_none_or(|failed| Some(failed) == goal) {
+                state.arrival = Some(false);
+            }
+        }
+    }
+}
+
+type TreeNpcQuery<'a> = (
+    Entity,
+    &'a Transform,
+    &'a mut Npc,
+    &'a mut Brain,
+    &'a mut Pathfinding,
+    &'a mut BehaviorTreeState,
+);
+
+/// Tick the behavior tree of every NPC that has one.
+///
+/// A tree that finishes starts again from its root in the next frame.
+pub fn run_behavior_trees(
+    clock: Res<GameClock>,
+    trees: Res<BehaviorTrees>,
+    mut planner: ActionPlanner,
+    mut npcs: Query<TreeNpcQuery>,
+) {
+    for (entity, transform, mut npc, mut brain, mut pathfinding, mut state) in &mut npcs {
+        let Some(tree) = trees.get(&state.tree) else {
+            continue;
+        };
+        let position = transform.translation;
+        let hour = clock.hour();
+        let checked_in = state.checked_in;
+        let had_lunch = state.had_lunch;
+        let needs = Need::ALL.map(|need| npc.needs.get(need));
+        let mut running = std::mem::take(&mut state.running);
+
+        tree.tick(
+            &mut running,
+            &mut |condition| match *condition {
+                TreeCondition::HourAfter(after) => hour >= after,
+                TreeCondition::HourBefore(before) => hour < before,
+                TreeCondition::CheckedIn(value) => checked_in == value,
+                TreeCondition::HadLunch(value) => had_lunch == value,
+                TreeCondition::NeedBelow { need, value } => needs[need as usize] < value,
+            },
+            &mut |action, resuming| match *action {
+                TreeAction::CheckIn => {
+                    state.checked_in = true;
+                    TreeStatus::Success
+                }
+                TreeAction::CheckOut => {
+                    state.checked_in = false;
+                    state.had_lunch = false;
+                    TreeStatus::Success
+                }
+                TreeAction::FinishLunch => {
+                    state.had_lunch = true;
+                    TreeStatus::Success
+                }
+                TreeAction::Wait(seconds) => {
+                    state.wait = if resuming {
+                        state.wait - clock.delta
+                    } else {
+                        seconds
+                    };
+                    if state.wait > 0.0 {
+                        TreeStatus::Running
+                    } else {
+                        TreeStatus::Success
+                    }
+                }
+                TreeAction::GoTo([x, z]) if !resuming => {
+                    let goal = Vec3::new(x, 0.0, z);
+                    planner.cancel(entity, &mut npc, &mut brain, &mut pathfinding);
+                    state.goal = Some(goal);
+                    state.arrival = None;
+                    pathfinding.final_facing = None;
+                    planner.walk(entity, position, goal, &mut npc);
+                    TreeStatus::Running
+                }
+                TreeAction::GoTo(_) => match state.arrival {
+                    Some(true) => TreeStatus::Success,
+                    Some(false) => TreeStatus::Failure,
+                    None => TreeStatus::Running,
+                },
+                TreeAction::Perform(_) | TreeAction::Decide if resuming => {
+                    if brain.action.is_some() {
+                        TreeStatus::Running
+                    } else if brain.failed {
+                        TreeStatus::Failure
+                    } else {
+                        TreeStatus::Success
+                    }
+                }
+                TreeAction::Perform(_) | TreeAction::Decide => {
+                    // a higher priority branch only takes over from an action still going on when it starts
+                    let started = match *action {
+                        TreeAction::Perform(action) => planner.start(
+                            action,
+                            entity,
+                            position,
+                            &mut npc,
+                            &mut brain,
+                            &mut pathfinding,
+                        ),
+                        _ => planner.decide(
+                            entity,
+                            position,
+                            hour,
+                            &mut npc,
+                            &mut brain,
+                            &mut pathfinding,
+                        ),
+                    };
+                    if started {
+                        state.goal = None;
+                        TreeStatus::Running
+                    } else {
+                        TreeStatus::Failure
+                    }
+                }
+            },
+        );
+        state.running = running;
+    }
+}
+
+/// Seconds of game time an NPC waits before trying again a target it could not reach
+pub const FAILED_TARGET_COOLDOWN: f32 = 600.0;
+
+/// Start the actions of the NPCs that arrived to their target and finish them when their time runs out.
+///
+/// NPCs that could not reach their target give up the action and block the target for a while.
+pub fn perform_actions(
+    clock: Res<GameClock>,
+    mut furniture_query: Query<&mut Furniture>,
+    mut npcs: Query<(Entity, &mut Npc, &mut Brain)>,
+    mut destination_reached: EventReader<DestinationReached>,
+    mut path_failed: EventReader<PathFailed>,
+) {
+    for event in destination_reached.iter() {
+        if let Ok((_, mut npc, brain)) = npcs.get_mut(event.entity) {
+            if brain.action.is_some() {
+                npc.behavior_state = BehaviorState::Working;
+            }
+        }
+    }
+    let failed: Vec<Entity> = path_failed.iter().map(|event| event.entity).collect();
+
+    for (entity, mut npc, mut brain) in &mut npcs {
+        brain.cool_down(clock.delta);
+        if brain.action.is_none() {
+            continue;
+        }
+        if npc.behavior_state == BehaviorState::Working {
+            brain.remaining -= clock.delta;
+        }
+        if brain.remaining > 0.0 && !failed.contains(&entity) {
+            continue;
+        }
+
+        if let Some(ActionTarget::Furniture { entity: target, .. }) = brain.target {
+            if let Ok(mut furniture) = furniture_query.get_mut(target) {
+                furniture.release_slot(entity);
+            }
+        }
+        // the scores would pick the same place again right away, so it is left alone for a while
+        if let Some(target) = brain.target.filter(|_| failed.contains(&entity)) {
+            brain.block(target, FAILED_TARGET_COOLDOWN);
+        }
+        brain.clear();
+        brain.failed = failed.contains(&entity);
+        npc.behavior_state = BehaviorState::Idle;
+    }
+}
+
+/// NPCs closer than this many times the sum of their radii steer around each other
+const AVOIDANCE_RANGE: f32 = 2.0;
+/// How strongly the avoidance bends the walking direction
+const AVOIDANCE_WEIGHT: f32 = 1.5;
+/// Distance to a step of the path, other than the last one, at which it counts as reached
+const WAYPOINT_TOLERANCE: f32 = 0.1;
+/// Tiles around the goal of a flow field where a crowd gathers, NPCs stop there when the way on is taken
+const CROWD_RADIUS: i32 = 3;
+
+/// Handles the path following for each walking NPC
+///
+/// NPCs following a flow field walk to the next tile of the field instead of the next step of their path,
+/// and near the goal they stop once the next tile is taken by an NPC standing there.
+/// The walking direction is bent by [avoidance_direction] so NPCs do not walk through each other, unless
+/// that would step into an obstructed tile.
+/// When the last step is within the arrival tolerance the NPC turns to its final facing, its path is
+/// cleared and it goes back to idle. Reaching the end of a partial path is a [PathFailed], not an arrival.
 pub fn handle_walking(
-    mut npcs: Query<(&mut Transform, &mut Pathfinding, &Npc), With<Npc>>,
+    mut npcs: Query<(Entity, &mut Transform, &mut Pathfinding, &mut Npc)>,
     time: Res<Time>,
+    grid: Res<Grid>,
+    flow_fields: Res<FlowFields>,
+    mut waypoint_reached: EventWriter<WaypointReached>,
+    mut destination_reached: EventWriter<DestinationReached>,
+    mut path_failed: EventWriter<PathFailed>,
 ) {
-    for (mut transform, mut pathfinding, npc) in npcs.iter_mut() {
-        // Ensure that this NPC is in walking state
+    let bodies: Vec<(Entity, Vec3, f32)> = npcs
+        .iter()
+        .map(|(entity, transform, _, npc)| (entity, transform.translation, npc.radius))
+        .collect();
+    let standing: Vec<(i32, i32)> = npcs
+        .iter()
+        .filter(|(_, _, _, npc)| npc.behavior_state != BehaviorState::Walking)
+        .map(|(_, transform, ..)| {
+            let coord = grid.world_to_coord(transform.translation);
+            (coord[0], coord[1])
+        })
+        .collect();
+
+    for (entity, mut transform, mut pathfinding, mut npc) in npcs.iter_mut() {
         if npc.behavior_state != BehaviorState::Walking {
-            return;
+            continue;
         }
 
-        if let Some(path) = &pathfinding.path {
-            // Ensure the NPC still has steps to follow
-            if pathfinding.current_step < path.steps.len() {
-                let target = path.steps[pathfinding.current_step];
-                let direction = (target - transform.translation).normalize();
+        // Pick the point to walk to, whether it ends the walk and whether the walk stops short of the goal
+        let (target, is_last, partial) = if let Some(goal) = pathfinding.flow_goal {
+            // The field may still be building
+            let Some(field) = flow_fields.get(goal) else {
+                continue;
+            };
+            let coord = grid.world_to_coord(transform.translation);
+            let start = (coord[0], coord[1]);
+            // A crowd can not stand on a single tile, so the ones behind stop where the way on is taken
+            let crowded = |target: Vec3| {
+                let next = grid.world_to_coord(target);
+                (start.0 - goal.0).abs() <= CROWD_RADIUS
+                    && (start.1 - goal.1).abs() <= CROWD_RADIUS
+                    && standing.contains(&(next[0], next[1]))
+            };
+            match field
+                .next_waypoint(&grid, transform.translation)
+                .filter(|&target| !crowded(target))
+            {
+                Some(target) => (target, false, false),
+                // Reached the goal, the crowd around it or it can not be reached from here
+                None => {
+                    if field.cost(start) == Some(0)
+                        || field.next_waypoint(&grid, transform.translation).is_some()
+                    {
+                        destination_reached.send(DestinationReached {
+                            entity,
+                            position: transform.translation,
+                        });
+                        if let Some(facing) = pathfinding.final_facing {
+                            transform.look_to(facing, Vec3::Y);
+                        }
+                    } else {
+                        path_failed.send(PathFailed {
+                            entity,
+                            error: PathfindingError::GoalUnreachable { start, goal },
+                        });
+                    }
+                    pathfinding.finish();
+                    npc.behavior_state = BehaviorState::Idle;
+                    continue;
+                }
+            }
+        } else if let Some(path) = &pathfinding.path {
+            let Some(&target) = path.steps.get(pathfinding.current_step) else {
+                continue;
+            };
+            (
+                target,
+                pathfinding.current_step + 1 == path.steps.len(),
+                path.partial,
+            )
+        } else {
+            // Waiting for a path to be searched again
+            continue;
+        };
 
-                // Move the NPC towards the step
-                transform.translation += direction * npc.movement_speed * time.delta_seconds();
+        let tolerance = if is_last {
+            pathfinding.arrival_tolerance
+        } else {
+            WAYPOINT_TOLERANCE
+        };
 
-                // Calculate the desired rotation using `look_at`
-                let mut target_transform = Transform::from_translation(transform.translation);
-                target_transform.look_at(target, Vec3::Y);
-                let target_rotation = target_transform.rotation;
+        let offset = target - transform.translation;
+        if offset.length() > tolerance {
+            let neighbors: Vec<(Vec3, f32)> = bodies
+                .iter()
+                .filter(|(other, ..)| *other != entity)
+                .map(|&(_, position, radius)| (position, radius))
+                .collect();
+            let mut direction = avoidance_direction(
+                transform.translation,
+                npc.radius,
+                offset.normalize(),
+                &neighbors,
+            );
 
-                // Smoothly interpolate between current rotation and desired rotation
-                transform.rotation = transform
-                    .rotation
-                    .slerp(target_rotation, npc.rotation_speed * time.delta_seconds());
+            // Move the NPC towards the target, without walking past it
+            let distance = (npc.movement_speed * time.delta_seconds()).min(offset.length());
+            // Never sidestep into a wall, the path itself only crosses walkable tiles
+            let coord = grid.world_to_coord(transform.translation + direction * distance);
+            if grid.is_obstructed((coord[0], coord[1])) {
+                direction = offset.normalize();
+            }
+            transform.translation += direction * distance;
 
-                // Check if NPC has reached the step (use a small error margin)
-                if transform.translation.distance(target) < 0.1 {
-                    pathfinding.current_step += 1;
-                }
+            // Smoothly turn to the walking direction
+            if direction != Vec3::ZERO {
+                let target_rotation = Transform::IDENTITY.looking_to(direction, Vec3::Y).rotation;
+                transform.rotation = transform.rotation.slerp(
+                    target_rotation,
+                    (npc.rotation_speed * time.delta_seconds()).min(1.0),
+                );
+            }
+        }
+
+        // Flow fields pick the next tile by themselves in the next frame
+        if pathfinding.flow_goal.is_some() || transform.translation.distance(target) > tolerance {
+            continue;
+        }
+
+        waypoint_reached.send(WaypointReached {
+            entity,
+            step: pathfinding.current_step,
+            position: target,
+        });
+        pathfinding.current_step += 1;
+
+        if is_last && partial {
+            // the search ran out of budget, so the NPC only got as close as it could
+            let coord = grid.world_to_coord(transform.translation);
+            let goal = pathfinding
+                .request
+                .map_or(coord, |request| grid.world_to_coord(request.goal));
+            path_failed.send(PathFailed {
+                entity,
+                error: PathfindingError::BudgetExhausted {
+                    start: (coord[0], coord[1]),
+                    goal: (goal[0], goal[1]),
+                },
+            });
+            pathfinding.finish();
+            npc.behavior_state = BehaviorState::Idle;
+        } else if is_last {
+            destination_reached.send(DestinationReached {
+                entity,
+                position: transform.translation,
+            });
+            if let Some(facing) = pathfinding.final_facing {
+                transform.look_to(facing, Vec3::Y);
             }
+            pathfinding.finish();
+            npc.behavior_state = BehaviorState::Idle;
+        }
+    }
+}
+
+/// Bend a walking direction away from the nearby NPCs, given by their position and radius.
+///
+/// Close neighbors push the NPC away and neighbors ahead make it sidestep to its right, so two NPCs
+/// walking toward each other pass side by side instead of overlapping.
+pub fn avoidance_direction(
+    position: Vec3,
+    radius: f32,
+    direction: Vec3,
+    neighbors: &[(Vec3, f32)],
+) -> Vec3 {
+    let right = direction.cross(Vec3::Y).normalize_or_zero();
+    let mut steering = Vec3::ZERO;
+
+    for &(other, other_radius) in neighbors {
+        let mut offset = position - other;
+        offset.y = 0.0;
+        let range = (radius + other_radius) * AVOIDANCE_RANGE;
+        let distance = offset.length();
+        if distance >= range {
+            continue;
+        }
+
+        let strength = (range - distance) / range;
+        steering += offset.try_normalize().unwrap_or(right) * strength;
+        if direction.dot(-offset) > 0.0 {
+            steering += right * strength;
+        }
+    }
+
+    (direction + steering * AVOIDANCE_WEIGHT).normalize_or_zero()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        npc::pathfinding::systems::{
+            dispatch_path_requests, handle_pathfinding_tasks, start_flow_field_walks,
+            update_flow_fields,
+        },
+        world::{
+            furniture::components::FurnitureKind,
+            grid::{
+                events::GridChanged,
+                hierarchical::HierarchicalGraph,
+                resources::{GridSnapshot, Path, PathRequest},
+            },
+            rooms::resources::RoomType,
+        },
+    };
+    use bevy::{diagnostic::DiagnosticsPlugin, time::TimeUpdateStrategy};
+    use hashbrown::HashSet;
+    use std::time::Duration;
+
+    /// Headless app running only the walking systems, with a fixed frame time
+    fn walking_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                50,
+            )))
+            .init_resource::<Grid>()
+            .init_resource::<FlowFields>()
+            .add_event::<PathStarted>()
+            .add_event::<WaypointReached>()
+            .add_event::<DestinationReached>()
+            .add_event::<PathFailed>()
+            .add_systems(Update, (handle_walking, handle_path_events).chain());
+        app
+    }
+
+    fn spawn_npc(app: &mut App, position: Vec3, state: BehaviorState, steps: Vec<Vec3>) -> Entity {
+        let mut npc = Npc::default();
+        npc.behavior_state = state;
+        app.world
+            .spawn((
+                Transform::from_translation(position),
+                npc,
+                Pathfinding {
+                    path: Some(Path {
+                        steps,
+                        partial: false,
+                    }),
+                    ..Default::default()
+                },
+            ))
+            .id()
+    }
+
+    #[test]
+    fn crowds_walk_a_shared_flow_field_to_the_goal() {
+        let mut app = walking_app();
+        app.add_event::<GridChanged>().add_systems(
+            Update,
+            (update_flow_fields, start_flow_field_walks).before(handle_path_events),
+        );
+        let goal = (10, 10);
+        let npcs: Vec<Entity> = [
+            Vec3::new(0.1, 0.0, 0.1),
+            Vec3::new(4.1, 0.0, 0.1),
+            Vec3::new(0.1, 0.0, 4.1),
+            Vec3::new(4.1, 0.0, 4.1),
+        ]
+        .into_iter()
+        .map(|position| {
+            let mut pathfinding = Pathfinding::default();
+            pathfinding.follow_flow_field(goal);
+            app.world
+                .spawn((
+                    Transform::from_translation(position),
+                    Npc::default(),
+                    pathfinding,
+                ))
+                .id()
+        })
+        .collect();
+        let mut reader = app
+            .world
+            .resource::<Events<DestinationReached>>()
+            .get_reader();
+
+        let mut arrived = Vec::new();
+        for _ in 0..400 {
+            app.update();
+            arrived.extend(
+                reader
+                    .iter(app.world.resource::<Events<DestinationReached>>())
+                    .map(|event| event.entity),
+            );
+        }
+
+        // Nobody follows the field anymore, so it is dropped
+        assert!(app.world.resource::<FlowFields>().fields.is_empty());
+        for entity in npcs {
+            assert!(arrived.contains(&entity));
+            let pathfinding = app.world.get::<Pathfinding>(entity).unwrap();
+            assert_eq!(pathfinding.flow_goal, None);
+            assert_eq!(
+                app.world.get::<Npc>(entity).unwrap().behavior_state,
+                BehaviorState::Idle
+            );
+        }
+    }
+
+    #[test]
+    fn cached_flow_fields_are_dropped_when_the_grid_changes() {
+        let mut app = App::new();
+        app.init_resource::<Grid>()
+            .init_resource::<FlowFields>()
+            .add_event::<GridChanged>()
+            .add_systems(Update, update_flow_fields);
+        let goal = (10, 10);
+        let mut pathfinding = Pathfinding::default();
+        pathfinding.follow_flow_field(goal);
+        app.world.spawn(pathfinding);
+        let wall = (5, 10);
+
+        app.update();
+        assert!(app
+            .world
+            .resource::<FlowFields>()
+            .get(goal)
+            .unwrap()
+            .cost(wall)
+            .is_some());
+
+        app.world.resource_mut::<Grid>().set_obstructed(wall, true);
+        let tiles: HashSet<(i32, i32)> = [wall].into_iter().collect();
+        app.world.send_event(GridChanged {
+            tiles: tiles.clone(),
+            obstructed: tiles,
+            doors: HashSet::new(),
+        });
+        app.update();
+
+        // Rebuilt around the wall
+        assert_eq!(
+            app.world
+                .resource::<FlowFields>()
+                .get(goal)
+                .unwrap()
+                .cost(wall),
+            None
+        );
+    }
+
+    #[test]
+    fn idle_npcs_do_not_stop_the_others() {
+        let mut app = walking_app();
+        let idle = spawn_npc(&mut app, Vec3::ZERO, BehaviorState::Idle, vec![Vec3::X]);
+        let walking = spawn_npc(
+            &mut app,
+            Vec3::new(0.0, 0.0, 5.0),
+            BehaviorState::Walking,
+            vec![Vec3::new(1.0, 0.0, 5.0)],
+        );
+
+        for _ in 0..5 {
+            app.update();
+        }
+
+        assert_eq!(
+            app.world.get::<Transform>(idle).unwrap().translation,
+            Vec3::ZERO
+        );
+        assert!(app.world.get::<Transform>(walking).unwrap().translation.x > 0.0);
+    }
+
+    #[test]
+    fn npcs_finish_their_path() {
+        let mut app = walking_app();
+        let steps = vec![Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.5, 0.0, 0.5)];
+        let npc = spawn_npc(&mut app, Vec3::ZERO, BehaviorState::Walking, steps);
+        app.world.get_mut::<Pathfinding>(npc).unwrap().final_facing = Some(Vec3::NEG_X);
+
+        let mut reader = app
+            .world
+            .resource::<Events<DestinationReached>>()
+            .get_reader();
+        let mut arrivals = 0;
+        for _ in 0..100 {
+            app.update();
+            arrivals += reader.iter(app.world.resource()).count();
+        }
+        assert_eq!(arrivals, 1);
+
+        let transform = app.world.get::<Transform>(npc).unwrap();
+        assert!(transform.translation.distance(Vec3::new(0.5, 0.0, 0.5)) <= 0.05);
+        assert!(transform.forward().distance(Vec3::NEG_X) < 1e-4);
+
+        let pathfinding = app.world.get::<Pathfinding>(npc).unwrap();
+        assert!(pathfinding.path.is_none());
+        assert_eq!(pathfinding.current_step, 0);
+        assert_eq!(
+            app.world.get::<Npc>(npc).unwrap().behavior_state,
+            BehaviorState::Idle
+        );
+    }
+
+    #[test]
+    fn walks_announce_the_start_each_step_and_the_arrival_in_order() {
+        #[derive(Resource, Default)]
+        struct WalkLog(Vec<String>);
+
+        fn record(
+            mut log: ResMut<WalkLog>,
+            mut path_started: EventReader<PathStarted>,
+            mut waypoint_reached: EventReader<WaypointReached>,
+            mut destination_reached: EventReader<DestinationReached>,
+            mut path_failed: EventReader<PathFailed>,
+        ) {
+            // Within a frame they can only happen in this order
+            log.0
+                .extend(path_started.iter().map(|_| "started".to_string()));
+            log.0.extend(
+                waypoint_reached
+                    .iter()
+                    .map(|event| format!("step {}", event.step)),
+            );
+            log.0
+                .extend(destination_reached.iter().map(|_| "arrived".to_string()));
+            log.0
+                .extend(path_failed.iter().map(|_| "failed".to_string()));
+        }
+
+        let mut app = walking_app();
+        app.add_plugins(DiagnosticsPlugin)
+            .init_resource::<HierarchicalGraph>()
+            .init_resource::<GridSnapshot>()
+            .init_resource::<PathfindingQueue>()
+            .init_resource::<WalkLog>()
+            .add_systems(
+                Update,
+                (
+                    (dispatch_path_requests, handle_pathfinding_tasks)
+                        .chain()
+                        .before(handle_walking),
+                    record.after(handle_path_events),
+                ),
+            );
+        let npc = app
+            .world
+            .spawn((Transform::default(), Npc::default(), Pathfinding::default()))
+            .id();
+        app.world.resource_mut::<PathfindingQueue>().push(
+            npc,
+            PathRequest::new(Vec3::ZERO, Vec3::new(2.0, 0.0, 1.0)),
+            PathPriority::Normal,
+        );
+
+        for _ in 0..300 {
+            app.update();
         }
+
+        let log = &app.world.resource::<WalkLog>().0;
+        assert!(log.len() > 3, "{log:?}");
+        let steps = log.len() - 2;
+        let mut expected = vec!["started".to_string()];
+        expected.extend((0..steps).map(|step| format!("step {step}")));
+        expected.push("arrived".to_string());
+        assert_eq!(log, &expected);
+    }
+
+    #[test]
+    fn partial_paths_do_not_arrive() {
+        let mut app = walking_app();
+        let npc = spawn_npc(
+            &mut app,
+            Vec3::ZERO,
+            BehaviorState::Walking,
+            vec![Vec3::new(0.5, 0.0, 0.0)],
+        );
+        app.world
+            .get_mut::<Pathfinding>(npc)
+            .unwrap()
+            .path
+            .as_mut()
+            .unwrap()
+            .partial = true;
+
+        let mut arrivals = app
+            .world
+            .resource::<Events<DestinationReached>>()
+            .get_reader();
+        let mut failures = app.world.resource::<Events<PathFailed>>().get_reader();
+        let (mut arrived, mut failed) = (0, 0);
+        for _ in 0..100 {
+            app.update();
+            arrived += arrivals.iter(app.world.resource()).count();
+            failed += failures.iter(app.world.resource()).count();
+        }
+
+        assert_eq!((arrived, failed), (0, 1));
+        assert_eq!(
+            app.world.get::<Npc>(npc).unwrap().behavior_state,
+            BehaviorState::Idle
+        );
+    }
+
+    #[test]
+    fn avoidance_does_not_push_npcs_into_walls() {
+        let mut app = walking_app();
+        let mut grid = app.world.resource_mut::<Grid>();
+        for x in -5..=15 {
+            grid.set_obstructed((x, 1), true);
+        }
+        // the idle NPC stands right below the way of the walking one, pushing it towards the wall
+        spawn_npc(
+            &mut app,
+            Vec3::new(1.0, 0.0, 0.0),
+            BehaviorState::Idle,
+            vec![],
+        );
+        let walking = spawn_npc(
+            &mut app,
+            Vec3::new(0.1, 0.0, 0.1),
+            BehaviorState::Walking,
+            vec![Vec3::new(2.1, 0.0, 0.1)],
+        );
+
+        for _ in 0..100 {
+            app.update();
+            let position = app.world.get::<Transform>(walking).unwrap().translation;
+            let coord = app.world.resource::<Grid>().world_to_coord(position);
+            assert!(!app
+                .world
+                .resource::<Grid>()
+                .is_obstructed((coord[0], coord[1])));
+        }
+        let position = app.world.get::<Transform>(walking).unwrap().translation;
+        assert!(position.distance(Vec3::new(2.1, 0.0, 0.1)) <= 0.05);
+    }
+
+    #[test]
+    fn npcs_walking_toward_each_other_sidestep_to_opposite_sides() {
+        let a = Vec3::new(0.0, 0.0, 0.0);
+        let b = Vec3::new(0.3, 0.0, 0.0);
+
+        let a_direction = avoidance_direction(a, 0.1, Vec3::X, &[(b, 0.1)]);
+        let b_direction = avoidance_direction(b, 0.1, Vec3::NEG_X, &[(a, 0.1)]);
+
+        assert!(a_direction.z * b_direction.z < 0.0);
+        assert_eq!(avoidance_direction(a, 0.1, Vec3::X, &[]), Vec3::X);
+    }
+
+    #[test]
+    fn npcs_leave_alone_the_targets_they_could_not_reach() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs(1)))
+            .init_resource::<GameClock>()
+            .init_resource::<Grid>()
+            .init_resource::<Rooms>()
+            .init_resource::<PathfindingQueue>()
+            .add_event::<DestinationReached>()
+            .add_event::<PathFailed>()
+            .add_systems(
+                Update,
+                (advance_clock, perform_actions, choose_actions).chain(),
+            );
+
+        let coffee_machine = app
+            .world
+            .spawn((
+                Transform::default(),
+                Furniture::new(FurnitureKind::CoffeeMachine),
+            ))
+            .id();
+        let mut npc = Npc::default();
+        npc.needs.thirst = 10;
+        let entity = app
+            .world
+            .spawn((
+                Transform::from_xyz(1.0, 0.0, 1.0),
+                npc,
+                Brain::default(),
+                Pathfinding::default(),
+            ))
+            .id();
+        let goes_to_the_coffee_machine = |app: &App| {
+            matches!(
+                app.world.get::<Brain>(entity).unwrap().target,
+                Some(ActionTarget::Furniture { entity, .. }) if entity == coffee_machine
+            )
+        };
+
+        app.update();
+        assert!(goes_to_the_coffee_machine(&app));
+
+        app.world.send_event(PathFailed {
+            entity,
+            error: PathfindingError::GoalUnreachable {
+                start: (5, 5),
+                goal: (0, 0),
+            },
+        });
+        app.update();
+        assert!(!goes_to_the_coffee_machine(&app));
+        assert!(app
+            .world
+            .get::<Furniture>(coffee_machine)
+            .unwrap()
+            .users
+            .is_empty());
+
+        // one minute of game time passes every frame
+        for _ in 0..(FAILED_TARGET_COOLDOWN / 60.0) as usize {
+            app.update();
+        }
+        assert!(goes_to_the_coffee_machine(&app));
+    }
+
+    /// Stand-in for the pathfinding, moves the NPCs straight to the goal of their requests
+    fn teleport_to_goals(
+        mut queue: ResMut<PathfindingQueue>,
+        mut transforms: Query<&mut Transform>,
+        mut destination_reached: EventWriter<DestinationReached>,
+    ) {
+        while let Some(queued) = queue.pop() {
+            if let Ok(mut transform) = transforms.get_mut(queued.entity) {
+                transform.translation = queued.request.goal;
+                destination_reached.send(DestinationReached {
+                    entity: queued.entity,
+                    position: queued.request.goal,
+                });
+            }
+        }
+    }
+
+    /// Headless app running the office day tree of an NPC with a desk, one minute of game time passes every frame
+    fn office_day_app(grid: Grid, rooms: Rooms, npc: Npc) -> (App, Entity, Entity) {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs(1)))
+            .insert_resource(BehaviorTrees::load("./config/behavior_trees.json"))
+            .insert_resource(grid)
+            .insert_resource(rooms)
+            .init_resource::<GameClock>()
+            .init_resource::<PathfindingQueue>()
+            .add_event::<DestinationReached>()
+            .add_event::<PathFailed>()
+            .add_systems(
+                Update,
+                (
+                    advance_clock,
+                    teleport_to_goals,
+                    perform_actions,
+                    record_tree_arrivals,
+                    run_behavior_trees,
+                )
+                    .chain(),
+            );
+
+        let desk = app
+            .world
+            .spawn((
+                Transform::from_xyz(2.0, 0.0, 2.0),
+                Furniture::new(FurnitureKind::Desk),
+            ))
+            .id();
+        let entity = app
+            .world
+            .spawn((
+                Transform::from_xyz(0.0, 0.0, 0.0),
+                npc,
+                Brain::default(),
+                Pathfinding::default(),
+                BehaviorTreeState::new("office_day"),
+            ))
+            .id();
+        (app, desk, entity)
+    }
+
+    fn run_until(app: &mut App, hour: f32) {
+        while app.world.resource::<GameClock>().hour() < hour {
+            app.update();
+        }
+    }
+
+    fn is_using(app: &App, furniture: Entity, entity: Entity) -> bool {
+        app.world
+            .get::<Furniture>(furniture)
+            .unwrap()
+            .users
+            .iter()
+            .any(|&(user, _)| user == entity)
+    }
+
+    #[test]
+    fn office_day_checks_in_works_has_lunch_and_leaves() {
+        let mut grid = Grid::default();
+        let mut rooms = Rooms::default();
+        for x in 20..=24 {
+            grid.set_obstructed((x, 20), true);
+            grid.set_obstructed((x, 24), true);
+        }
+        for z in 20..=24 {
+            grid.set_obstructed((20, z), true);
+            grid.set_obstructed((24, z), true);
+        }
+        rooms.detect(&mut grid);
+        let kitchen = grid.room((22, 22)).unwrap();
+        rooms.designate(kitchen, Some(RoomType::Kitchen));
+        let (mut app, desk, entity) = office_day_app(grid, rooms, Npc::default());
+
+        let action = |app: &App| app.world.get::<Brain>(entity).unwrap().action;
+        let state = |app: &App| {
+            let state = app.world.get::<BehaviorTreeState>(entity).unwrap();
+            (state.checked_in, state.had_lunch)
+        };
+
+        run_until(&mut app, 8.1);
+        assert_eq!(state(&app), (true, false));
+        assert_eq!(action(&app), Some(Action::Work));
+        assert!(is_using(&app, desk, entity));
+
+        // lunch takes over from the work going on
+        run_until(&mut app, 12.05);
+        assert_eq!(action(&app), Some(Action::Eat));
+        assert!(!is_using(&app, desk, entity));
+        let position = app.world.get::<Transform>(entity).unwrap().translation;
+        let tile = app.world.resource::<Grid>().world_to_coord(position);
+        assert_eq!(
+            app.world.resource::<Grid>().room((tile[0], tile[1])),
+            Some(kitchen)
+        );
+
+        // lunch is eaten once, the NPC goes back to work before one o'clock
+        run_until(&mut app, 12.75);
+        assert_eq!(state(&app), (true, true));
+        assert_eq!(action(&app), Some(Action::Work));
+
+        // leaving takes over from the work at the end of the day
+        run_until(&mut app, 17.1);
+        assert_eq!(state(&app), (false, false));
+        assert_eq!(action(&app), None);
+        assert!(!is_using(&app, desk, entity));
+        assert_eq!(
+            app.world.get::<Transform>(entity).unwrap().translation,
+            Vec3::ZERO
+        );
+    }
+
+    #[test]
+    fn branches_that_can_not_start_do_not_take_over() {
+        // there is no kitchen for lunch nor lounge or chair to rest
+        let mut npc = Npc::default();
+        npc.needs.energy = 10;
+        let (mut app, desk, entity) = office_day_app(Grid::default(), Rooms::default(), npc);
+
+        run_until(&mut app, 12.0);
+        let mut interruptions = 0;
+        while app.world.resource::<GameClock>().hour() < 13.0 {
+            app.update();
+            let brain = app.world.get::<Brain>(entity).unwrap();
+            let npc = app.world.get::<Npc>(entity).unwrap();
+            if brain.action != Some(Action::Work) || !is_using(&app, desk, entity) {
+                interruptions += 1;
+            }
+            assert!(brain.action.is_some() || npc.behavior_state != BehaviorState::Working);
+        }
+
+        // only the end of a whole hour of work may leave the desk for a frame
+        assert!(
+            interruptions <= 1,
+            "work was interrupted {interruptions} times"
+        );
     }
 }
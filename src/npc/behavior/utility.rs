@@ -0,0 +1,209 @@
+//! Utility AI that decides what an idle NPC does next.
+//!
+//! Every [Action] gets a score between 0 and 1 from the needs, attributes and role of the NPC and the time of
+//! day. The scores only depend on their inputs, so the same NPC at the same hour always picks the same action.
+
+use serde::Deserialize;
+
+use crate::{
+    npc::components::{Atributtes, Need, Needs, Role},
+    world::{furniture::components::FurnitureKind, rooms::resources::RoomType},
+};
+
+/// Something an NPC can decide to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Work,
+    Eat,
+    Drink,
+    Rest,
+    Socialise,
+    Meeting,
+}
+
+/// Where an action can be done, a free slot of a piece of furniture or any valid room of a type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionPlace {
+    Furniture(FurnitureKind),
+    Room(RoomType),
+}
+
+/// Everything the scores depend on.
+pub struct UtilityInput<'a> {
+    pub needs: &'a Needs,
+    pub atributtes: &'a Atributtes,
+    pub role: Role,
+    /// Hour of the day, from 0 to 24
+    pub hour: f32,
+}
+
+impl UtilityInput<'_> {
+    /// How much a need asks to be satisfied, 0 when full and 1 when empty
+    fn urgency(&self, need: Need) -> f32 {
+        1.0 - self.needs.get(need).min(100) as f32 / 100.0
+    }
+
+    fn working_hours(&self) -> bool {
+        (9.0..18.0).contains(&self.hour)
+    }
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::Work,
+        Action::Eat,
+        Action::Drink,
+        Action::Rest,
+        Action::Socialise,
+        Action::Meeting,
+    ];
+
+    /// Return how much the NPC wants to do the action, from 0 to 1
+    pub fn score(&self, input: &UtilityInput) -> f32 {
+        let score = match self {
+            Action::Work => {
+                if !input.working_hours() {
+                    0.05
+                } else {
+                    let role = if input.role == Role::None { 0.5 } else { 1.0 };
+                    0.6 * role * (1.0 - input.urgency(Need::Focus) * 0.5)
+                }
+            }
+            Action::Eat => {
+                let lunch = if (12.0..14.0).contains(&input.hour) {
+                    0.3
+                } else {
+                    0.0
+                };
+                input.urgency(Need::Hungry).powi(2) + lunch
+            }
+            Action::Drink => input.urgency(Need::Thirst).powi(2),
+            Action::Rest => {
+                let evening = if input.hour >= 18.0 { 0.3 } else { 0.0 };
+                input.urgency(Need::Energy).powi(2) + evening
+            }
+            Action::Socialise => {
+                let social = input.atributtes.social.min(100) as f32 / 100.0;
+                input.urgency(Need::Creativity) * (0.4 + 0.4 * social)
+            }
+            Action::Meeting => {
+                if !input.working_hours() {
+                    0.0
+                } else {
+                    let role = match input.role {
+                        Role::Marketing | Role::Design | Role::HumanResources => 0.5,
+                        Role::None => 0.1,
+                        _ => 0.3,
+                    };
+                    role * (0.5 + 0.5 * input.urgency(Need::Creativity))
+                }
+            }
+        };
+
+        score.clamp(0.0, 1.0)
+    }
+
+    /// Places where the action can be done, the first ones are preferred
+    pub fn places(&self) -> &'static [ActionPlace] {
+        match self {
+            Action::Work => &[ActionPlace::Furniture(FurnitureKind::Desk)],
+            Action::Eat => &[ActionPlace::Room(RoomType::Kitchen)],
+            Action::Drink => &[
+                ActionPlace::Furniture(FurnitureKind::CoffeeMachine),
+                ActionPlace::Room(RoomType::Kitchen),
+            ],
+            Action::Rest => &[
+                ActionPlace::Room(RoomType::Lounge),
+                ActionPlace::Furniture(FurnitureKind::Chair),
+            ],
+            Action::Socialise => &[ActionPlace::Room(RoomType::Lounge)],
+            Action::Meeting => &[ActionPlace::Furniture(FurnitureKind::Whiteboard)],
+        }
+    }
+
+    /// Seconds of game time the NPC spends doing the action once it arrives
+    pub fn duration(&self) -> f32 {
+        match self {
+            Action::Work => 3600.0,
+            Action::Eat => 1800.0,
+            Action::Drink => 300.0,
+            Action::Rest => 1800.0,
+            Action::Socialise => 900.0,
+            Action::Meeting => 2400.0,
+        }
+    }
+}
+
+/// Return every action with its score, the best first.
+///
+/// Ties keep the order of [Action::ALL], so the ranking is deterministic.
+pub fn rank_actions(input: &UtilityInput) -> Vec<(Action, f32)> {
+    let mut ranking: Vec<(Action, f32)> = Action::ALL
+        .iter()
+        .map(|&action| (action, action.score(input)))
+        .collect();
+    ranking.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranking
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::npc::components::Npc;
+
+    fn best_action(npc: &Npc, hour: f32) -> Action {
+        let input = UtilityInput {
+            needs: &npc.needs,
+            atributtes: &npc.atributtes,
+            role: npc.role,
+            hour,
+        };
+        rank_actions(&input)[0].0
+    }
+
+    #[test]
+    fn satisfied_npcs_work_during_working_hours() {
+        let mut npc = Npc::default();
+        npc.role = Role::Tech;
+
+        assert_eq!(best_action(&npc, 10.0), Action::Work);
+    }
+
+    #[test]
+    fn urgent_needs_win_over_work() {
+        let mut npc = Npc::default();
+        npc.role = Role::Tech;
+
+        npc.needs.thirst = 10;
+        assert_eq!(best_action(&npc, 10.0), Action::Drink);
+
+        npc.needs.thirst = 100;
+        npc.needs.hungry = 40;
+        assert_eq!(best_action(&npc, 10.0), Action::Work);
+        assert_eq!(best_action(&npc, 13.0), Action::Eat);
+    }
+
+    #[test]
+    fn npcs_rest_after_working_hours() {
+        let npc = Npc::default();
+
+        assert_eq!(best_action(&npc, 20.0), Action::Rest);
+    }
+
+    #[test]
+    fn scores_are_deterministic() {
+        let npc = Npc::default();
+        let input = UtilityInput {
+            needs: &npc.needs,
+            atributtes: &npc.atributtes,
+            role: npc.role,
+            hour: 11.0,
+        };
+
+        assert_eq!(rank_actions(&input), rank_actions(&input));
+        assert!(rank_actions(&input)
+            .iter()
+            .all(|&(_, score)| (0.0..=1.0).contains(&score)));
+    }
+}
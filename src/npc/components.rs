@@ -1,14 +1,16 @@
 use bevy::prelude::*;
+use serde::Deserialize;
 
 use super::behavior::states::BehaviorState;
 /// needs is the basic needs of the npc
 /// Atributtes is the rpg atributtes for the npc that will use to calc your performance
-/// experience grind is calculated using the formula: (level/xp_factor) ^ xp_power
+/// experience grind is calculated using the formula: ((level + 1)/xp_factor) ^ xp_power
 #[derive(Component)]
 pub struct Npc {
     pub experience: u32,
     pub level: u32,
     pub role: Role,
+    pub personality: Personality,
     pub needs: Needs,
     pub atributtes: Atributtes,
 
@@ -18,6 +20,8 @@ pub struct Npc {
     pub behavior_state: BehaviorState,
     pub movement_speed: f32,
     pub rotation_speed: f32,
+    /// Radius of the space taken by the NPC, other NPCs steer around it
+    pub radius: f32,
 }
 
 const XP_FACTOR: f32 = 0.1;
@@ -29,9 +33,11 @@ impl Default for Npc {
             behavior_state: BehaviorState::Idle,
             movement_speed: 0.5,
             rotation_speed: 15.0,
+            radius: 0.1,
             experience: 0,
             level: 0,
             role: Role::None,
+            personality: Personality::Balanced,
             xp_factor: XP_FACTOR,
             xp_power: XP_POWER,
             needs: Needs {
@@ -53,19 +59,26 @@ impl Default for Npc {
     }
 }
 impl Npc {
-    pub fn add_experience(&mut self, amount: u32) {
+    /// Add experience and level up as many times as it reaches, returning the levels gained
+    pub fn add_experience(&mut self, amount: u32) -> u32 {
         self.experience += amount;
 
+        let mut levels = 0;
         while self.experience >= self.experience_to_next_level() {
             self.experience -= self.experience_to_next_level();
             self.level += 1;
+            levels += 1;
         }
+        levels
     }
 
+    /// Experience needed to reach the next level, never 0
     pub fn experience_to_next_level(&self) -> u32 {
-        ((self.level as f32 / self.xp_factor).powf(self.xp_power)) as u32
+        let next_level = (self.level + 1) as f32;
+        ((next_level / self.xp_factor).powf(self.xp_power) as u32).max(1)
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Role {
     Marketing,
     CustomerAcquisition,
@@ -76,6 +89,21 @@ pub enum Role {
     SupportServices,
     None,
 }
+
+/// Temperament of the npc, changes how fast some needs decay
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Personality {
+    #[default]
+    Balanced,
+    /// Gets tired slowly
+    Energetic,
+    /// Gets hungry and thirsty fast
+    Glutton,
+    /// Keeps the focus for longer
+    Focused,
+    /// Keeps the creativity for longer but loses the focus fast
+    Dreamer,
+}
 #[derive(Component)]
 pub struct Needs {
     pub hungry: u32,
@@ -86,6 +114,53 @@ pub struct Needs {
     pub creativity: u32,
 }
 
+/// Names each field of [Needs], so rooms and actions can refer to the need they satisfy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Need {
+    Hungry,
+    Thirst,
+    Relief,
+    Energy,
+    Focus,
+    Creativity,
+}
+
+impl Need {
+    pub const ALL: [Need; 6] = [
+        Need::Hungry,
+        Need::Thirst,
+        Need::Relief,
+        Need::Energy,
+        Need::Focus,
+        Need::Creativity,
+    ];
+}
+
+impl Needs {
+    pub fn get(&self, need: Need) -> u32 {
+        match need {
+            Need::Hungry => self.hungry,
+            Need::Thirst => self.thirst,
+            Need::Relief => self.relief,
+            Need::Energy => self.energy,
+            Need::Focus => self.focus,
+            Need::Creativity => self.creativity,
+        }
+    }
+
+    pub fn get_mut(&mut self, need: Need) -> &mut u32 {
+        match need {
+            Need::Hungry => &mut self.hungry,
+            Need::Thirst => &mut self.thirst,
+            Need::Relief => &mut self.relief,
+            Need::Energy => &mut self.energy,
+            Need::Focus => &mut self.focus,
+            Need::Creativity => &mut self.creativity,
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct Atributtes {
     pub logic: u32,
@@ -94,3 +169,88 @@ pub struct Atributtes {
     pub practical: u32,
     pub artistic: u32,
 }
+
+/// Names each field of [Atributtes], so furniture and work can refer to the skills they use
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Attribute {
+    Logic,
+    Linguistic,
+    Social,
+    Practical,
+    Artistic,
+}
+
+impl Attribute {
+    pub const ALL: [Attribute; 5] = [
+        Attribute::Logic,
+        Attribute::Linguistic,
+        Attribute::Social,
+        Attribute::Practical,
+        Attribute::Artistic,
+    ];
+}
+
+impl Atributtes {
+    pub fn get(&self, attribute: Attribute) -> u32 {
+        match attribute {
+            Attribute::Logic => self.logic,
+            Attribute::Linguistic => self.linguistic,
+            Attribute::Social => self.social,
+            Attribute::Practical => self.practical,
+            Attribute::Artistic => self.artistic,
+        }
+    }
+
+    pub fn get_mut(&mut self, attribute: Attribute) -> &mut u32 {
+        match attribute {
+            Attribute::Logic => &mut self.logic,
+            Attribute::Linguistic => &mut self.linguistic,
+            Attribute::Social => &mut self.social,
+            Attribute::Practical => &mut self.practical,
+            Attribute::Artistic => &mut self.artistic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_level_needs_experience() {
+        let mut npc = Npc::default();
+
+        assert_eq!(npc.experience_to_next_level(), 100);
+        assert_eq!(npc.add_experience(99), 0);
+        assert_eq!(npc.add_experience(1), 1);
+        assert_eq!((npc.level, npc.experience), (1, 0));
+    }
+
+    #[test]
+    fn levels_get_harder_and_can_stack() {
+        let mut npc = Npc::default();
+        assert!(
+            Npc {
+                level: 1,
+                ..Default::default()
+            }
+            .experience_to_next_level()
+                > npc.experience_to_next_level()
+        );
+
+        // 100 for the first level and 400 for the second one
+        assert_eq!(npc.add_experience(550), 2);
+        assert_eq!((npc.level, npc.experience), (2, 50));
+    }
+
+    #[test]
+    fn experience_never_loops_forever() {
+        let mut npc = Npc {
+            xp_factor: 1000.0,
+            ..Default::default()
+        };
+
+        assert_eq!(npc.experience_to_next_level(), 1);
+        assert_eq!(npc.add_experience(3), 3);
+    }
+}
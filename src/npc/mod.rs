@@ -12,11 +12,19 @@ use states::*;
 pub mod behavior;
 use behavior::*;
 
+pub mod needs;
+use needs::*;
+
+pub mod work;
+use work::*;
+
 pub struct NpcPlugin;
 
 impl Plugin for NpcPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(PathfindingPlugin);
         app.add_plugins(BehaviorPlugin);
+        app.add_plugins(NeedsPlugin);
+        app.add_plugins(WorkPlugin);
     }
 }
@@ -0,0 +1,23 @@
+use bevy::prelude::*;
+
+use crate::npc::components::Need;
+
+/// Fractions of need points not applied yet to the integer needs of the NPC.
+#[derive(Component, Default)]
+pub struct NeedsProgress {
+    pending: [f32; Need::ALL.len()],
+}
+
+impl NeedsProgress {
+    /// Add a change to a need and return the whole points ready to apply
+    pub fn accumulate(&mut self, need: Need, amount: f32) -> i32 {
+        let index = Need::ALL
+            .iter()
+            .position(|&other| other == need)
+            .unwrap_or_default();
+        self.pending[index] += amount;
+        let whole = self.pending[index].trunc();
+        self.pending[index] -= whole;
+        whole as i32
+    }
+}
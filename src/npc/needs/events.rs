@@ -0,0 +1,14 @@
+use bevy::prelude::*;
+
+use crate::npc::components::Need;
+
+use super::resources::NeedLevel;
+
+/// Sent when a need of an NPC crosses a threshold, like becoming critically hungry or satisfied again.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct NeedLevelChanged {
+    pub entity: Entity,
+    pub need: Need,
+    pub level: NeedLevel,
+    pub value: u32,
+}
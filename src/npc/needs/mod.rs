@@ -0,0 +1,28 @@
+//! Simulates the needs of the NPCs.
+//!
+//! Needs decay over game time at rates that depend on the role and the personality of each NPC, and are
+//! replenished while the NPC uses the right furniture or stands in a room that satisfies them.
+use bevy::prelude::*;
+
+pub mod systems;
+use systems::*;
+pub mod components;
+use components::*;
+pub mod resources;
+use resources::*;
+pub mod states;
+use states::*;
+pub mod events;
+use events::*;
+
+use super::behavior::systems::advance_clock;
+
+pub struct NeedsPlugin;
+
+impl Plugin for NeedsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NeedsConfig>();
+        app.add_event::<NeedLevelChanged>();
+        app.add_systems(Update, update_needs.after(advance_clock));
+    }
+}
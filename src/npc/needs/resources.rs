@@ -0,0 +1,92 @@
+use bevy::prelude::*;
+use hashbrown::HashMap;
+
+use crate::npc::components::{Need, Personality, Role};
+
+/// How urgent a need is, from its value and the thresholds of the [NeedsConfig].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NeedLevel {
+    Critical,
+    Low,
+    Satisfied,
+}
+
+/// Rates of the needs simulation.
+///
+/// Rates are points per second of game time. The decay of a need is its base rate multiplied by the
+/// multipliers of the role and the personality of the NPC, missing multipliers count as 1.
+#[derive(Resource)]
+pub struct NeedsConfig {
+    pub base_decay: HashMap<Need, f32>,
+    pub role_multipliers: HashMap<(Role, Need), f32>,
+    pub personality_multipliers: HashMap<(Personality, Need), f32>,
+    /// Replenish rate of the needs satisfied by the room the NPC stands in
+    pub room_replenish: f32,
+    /// Needs at or below this value are low
+    pub low_threshold: u32,
+    /// Needs at or below this value are critical
+    pub critical_threshold: u32,
+}
+
+impl Default for NeedsConfig {
+    fn default() -> Self {
+        Self {
+            base_decay: HashMap::from([
+                (Need::Hungry, 0.005),
+                (Need::Thirst, 0.008),
+                (Need::Relief, 0.004),
+                (Need::Energy, 0.003),
+                (Need::Focus, 0.006),
+                (Need::Creativity, 0.004),
+            ]),
+            role_multipliers: HashMap::from([
+                ((Role::Tech, Need::Focus), 1.3),
+                ((Role::Finance, Need::Focus), 1.2),
+                ((Role::Design, Need::Creativity), 1.4),
+                ((Role::Marketing, Need::Creativity), 1.2),
+                ((Role::CustomerAcquisition, Need::Energy), 1.2),
+                ((Role::SupportServices, Need::Energy), 1.3),
+                ((Role::HumanResources, Need::Thirst), 1.2),
+            ]),
+            personality_multipliers: HashMap::from([
+                ((Personality::Energetic, Need::Energy), 0.7),
+                ((Personality::Glutton, Need::Hungry), 1.5),
+                ((Personality::Glutton, Need::Thirst), 1.5),
+                ((Personality::Focused, Need::Focus), 0.7),
+                ((Personality::Dreamer, Need::Creativity), 0.7),
+                ((Personality::Dreamer, Need::Focus), 1.3),
+            ]),
+            room_replenish: 0.05,
+            low_threshold: 40,
+            critical_threshold: 15,
+        }
+    }
+}
+
+impl NeedsConfig {
+    /// Return the points per second of game time a need decays for an NPC
+    pub fn decay_rate(&self, role: Role, personality: Personality, need: Need) -> f32 {
+        let base = self.base_decay.get(&need).copied().unwrap_or(0.0);
+        let role = self
+            .role_multipliers
+            .get(&(role, need))
+            .copied()
+            .unwrap_or(1.0);
+        let personality = self
+            .personality_multipliers
+            .get(&(personality, need))
+            .copied()
+            .unwrap_or(1.0);
+        base * role * personality
+    }
+
+    pub fn level(&self, value: u32) -> NeedLevel {
+        if value <= self.critical_threshold {
+            NeedLevel::Critical
+        } else if value <= self.low_threshold {
+            NeedLevel::Low
+        } else {
+            NeedLevel::Satisfied
+        }
+    }
+}
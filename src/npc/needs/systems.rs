@@ -0,0 +1,150 @@
+use bevy::prelude::*;
+use hashbrown::HashMap;
+
+use crate::{
+    npc::{
+        behavior::{resources::GameClock, states::BehaviorState},
+        components::{Need, Npc},
+    },
+    world::{furniture::components::Furniture, grid::resources::Grid, rooms::resources::Rooms},
+};
+
+use super::{components::NeedsProgress, events::NeedLevelChanged, resources::NeedsConfig};
+
+/// Highest value of a need
+const MAX_NEED: i64 = 100;
+
+/// Decay the needs of every NPC and replenish the ones satisfied by the furniture it uses and the room it is in.
+///
+/// Sends a [NeedLevelChanged] every time a need crosses one of the thresholds.
+pub fn update_needs(
+    clock: Res<GameClock>,
+    config: Res<NeedsConfig>,
+    grid: Res<Grid>,
+    rooms: Res<Rooms>,
+    furniture_query: Query<&Furniture>,
+    mut npcs: Query<(Entity, &Transform, &mut Npc, &mut NeedsProgress)>,
+    mut level_changed: EventWriter<NeedLevelChanged>,
+) {
+    let delta = clock.delta;
+    if delta <= 0.0 {
+        return;
+    }
+
+    let mut in_use: HashMap<Entity, Vec<(Need, f32)>> = HashMap::new();
+    for furniture in &furniture_query {
+        for &(user, _) in &furniture.users {
+            in_use
+                .entry(user)
+                .or_default()
+                .extend(furniture.needs.iter().copied());
+        }
+    }
+
+    for (entity, transform, mut npc, mut progress) in &mut npcs {
+        let coord = grid.world_to_coord(transform.translation);
+        let room = rooms.room_at(&grid, (coord[0], coord[1]));
+        // furniture only helps once the NPC arrived and is using it
+        let using = npc.behavior_state == BehaviorState::Working;
+
+        for need in Need::ALL {
+            let mut rate = -config.decay_rate(npc.role, npc.personality, need);
+            rate += in_use
+                .get(&entity)
+                .filter(|_| using)
+                .into_iter()
+                .flatten()
+                .filter(|(used_need, _)| *used_need == need)
+                .map(|(_, amount)| amount)
+                .sum::<f32>();
+            if room.is_some_and(|room| room.satisfies(need)) {
+                rate += config.room_replenish;
+            }
+
+            let change = progress.accumulate(need, rate * delta);
+            if change == 0 {
+                continue;
+            }
+
+            let value = npc.needs.get_mut(need);
+            let previous = *value;
+            *value = (previous as i64 + change as i64).clamp(0, MAX_NEED) as u32;
+
+            let level = config.level(*value);
+            if level != config.level(previous) {
+                level_changed.send(NeedLevelChanged {
+                    entity,
+                    need,
+                    level,
+                    value: *value,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::npc::{
+        behavior::systems::advance_clock,
+        components::{Personality, Role},
+        needs::resources::NeedLevel,
+    };
+    use bevy::time::TimeUpdateStrategy;
+    use std::time::Duration;
+
+    #[test]
+    fn needs_decay_by_role_and_personality() {
+        let config = NeedsConfig::default();
+        let base = config.decay_rate(Role::None, Personality::Balanced, Need::Focus);
+
+        assert!(config.decay_rate(Role::Tech, Personality::Balanced, Need::Focus) > base);
+        assert!(config.decay_rate(Role::None, Personality::Focused, Need::Focus) < base);
+    }
+
+    #[test]
+    fn critical_needs_send_an_event() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs(1)))
+            .insert_resource(GameClock {
+                time_scale: 10.0,
+                ..Default::default()
+            })
+            .insert_resource(NeedsConfig {
+                base_decay: HashMap::from([(Need::Thirst, 1.0)]),
+                ..Default::default()
+            })
+            .init_resource::<Grid>()
+            .init_resource::<Rooms>()
+            .add_event::<NeedLevelChanged>()
+            .add_systems(Update, (advance_clock, update_needs).chain());
+
+        let mut npc = Npc::default();
+        npc.needs.thirst = 20;
+        let entity = app
+            .world
+            .spawn((Transform::default(), npc, NeedsProgress::default()))
+            .id();
+
+        let mut reader = app
+            .world
+            .resource::<Events<NeedLevelChanged>>()
+            .get_reader();
+        let mut changes = Vec::new();
+        for _ in 0..3 {
+            app.update();
+            changes.extend(
+                reader
+                    .iter(app.world.resource())
+                    .map(|event| (event.need, event.level)),
+            );
+        }
+
+        let npc = app.world.get::<Npc>(entity).unwrap();
+        assert_eq!(npc.needs.thirst, 0);
+        assert_eq!(npc.needs.hungry, 100);
+        assert_eq!(changes, vec![(Need::Thirst, NeedLevel::Critical)]);
+    }
+}
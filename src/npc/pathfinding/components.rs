@@ -2,14 +2,33 @@ use bevy::{
     prelude::*,
     tasks::{AsyncComputeTaskPool, Task},
 };
+use hashbrown::HashSet;
 use pathfinding::*;
+use std::sync::Arc;
 
-use crate::world::grid::resources::{Grid, Path, PathfindingError};
+use crate::world::grid::{
+    hierarchical::HierarchicalGraph,
+    resources::{Grid, Path, PathRequest, PathSearch, PathfindingError},
+};
+
+use super::resources::PathPriority;
 
 #[derive(Component)]
 pub struct Pathfinding {
     pub path: Option<Path>,
     pub current_step: usize,
+    /// Why the last path request failed, cleared when a path is found
+    pub last_error: Option<PathfindingError>,
+    /// Request of the current path, used to search it again when the grid changes
+    pub request: Option<PathRequest>,
+    /// Priority of the current path, kept when it is searched again
+    pub priority: PathPriority,
+    /// Goal tile of the flow field being followed instead of the path
+    pub flow_goal: Option<(i32, i32)>,
+    /// Distance to the last step at which the destination counts as reached
+    pub arrival_tolerance: f32,
+    /// Direction to face after reaching the destination, like the desk the NPC walked to
+    pub final_facing: Option<Vec3>,
 }
 
 impl Default for Pathfinding {
@@ -17,38 +36,92 @@ impl Default for Pathfinding {
         Self {
             path: None,
             current_step: 0,
+            last_error: None,
+            request: None,
+            priority: PathPriority::default(),
+            flow_goal: None,
+            arrival_tolerance: 0.05,
+            final_facing: None,
         }
     }
 }
 
-impl Pathfinding {}
+impl Pathfinding {
+    /// Walk to a shared destination following its flow field instead of a path of its own.
+    ///
+    /// The NPC starts walking in the next frames, see [start_flow_field_walks](super::systems::start_flow_field_walks).
+    pub fn follow_flow_field(&mut self, goal: (i32, i32)) {
+        self.path = None;
+        self.current_step = 0;
+        self.request = None;
+        self.flow_goal = Some(goal);
+    }
+
+    /// Clear the path and the flow field once the destination is reached
+    pub fn finish(&mut self) {
+        self.path = None;
+        self.current_step = 0;
+        self.request = None;
+        self.flow_goal = None;
+        self.final_facing = None;
+    }
+
+    /// Return if there are steps of the path left to walk
+    pub fn is_walking(&self) -> bool {
+        self.path
+            .as_ref()
+            .is_some_and(|path| self.current_step < path.steps.len())
+    }
+
+    /// Return if the rest of the path walks over any of the tiles
+    pub fn crosses_tiles(&self, grid: &Grid, tiles: &HashSet<(i32, i32)>) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+
+        let first_step = self.current_step.saturating_sub(1);
+        path.steps
+            .get(first_step..)
+            .unwrap_or_default()
+            .windows(2)
+            .any(|pair| {
+                grid.tiles_on_segment(pair[0], pair[1])
+                    .iter()
+                    .any(|tile| tiles.contains(tile))
+            })
+    }
+}
 
 #[derive(Component)]
-pub struct PathfindingTask(pub Task<Result<Path, PathfindingError>>);
+pub struct PathfindingTask {
+    pub task: Task<PathSearch>,
+    pub request: PathRequest,
+    pub priority: PathPriority,
+}
 
 pub fn spawn_optimized_pathfinding_task(
     commands: &mut Commands,
     target: Entity,
-    grid: &Grid,
-    start: Vec3,
-    end: Vec3,
+    grid: &Arc<Grid>,
+    graph: &Arc<HierarchicalGraph>,
+    request: PathRequest,
+    priority: PathPriority,
 ) {
-    // Fail early if end is not valid
-    if grid.obstructed(&end) {
-        return;
-    }
-
     let thread_pool = AsyncComputeTaskPool::get();
 
-    // Must clone because the grid can change between frames
-    // Must box to prevent stack overflows on very large grids
-    let grid = Box::new(grid.clone());
+    // The snapshots are shared by all the tasks, the grid can change between frames without affecting them
+    let grid = grid.clone();
+    let graph = graph.clone();
 
     let task = thread_pool.spawn(async move {
-        let mut path = grid.find_path(&start, &end);
-        let _ = path.as_mut().map(|p| p.optimize_corners());
-        path
+        let mut search = graph.search(&grid, &request);
+        let _ = search.result.as_mut().map(|p| p.smooth(&grid));
+        search
     });
 
-    commands.entity(target).insert(PathfindingTask(task));
+    commands.entity(target).insert(PathfindingTask {
+        task,
+        request,
+        priority,
+    });
 }
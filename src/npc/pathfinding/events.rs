@@ -0,0 +1,34 @@
+use bevy::prelude::*;
+
+use crate::world::grid::resources::PathfindingError;
+
+/// Sent when a path was found for an entity and it can start walking it.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PathStarted {
+    pub entity: Entity,
+    /// Last step of the path, it may be short of the requested goal for partial paths
+    pub destination: Vec3,
+}
+
+/// Sent when an entity reaches a step of its path.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct WaypointReached {
+    pub entity: Entity,
+    pub step: usize,
+    pub position: Vec3,
+}
+
+/// Sent when an entity reaches the end of a complete path, or the goal of its flow field or the crowd around it.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct DestinationReached {
+    pub entity: Entity,
+    pub position: Vec3,
+}
+
+/// Sent when no path could be found for an entity, its flow field does not reach the goal or it walked to the
+/// end of a partial path.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PathFailed {
+    pub entity: Entity,
+    pub error: PathfindingError,
+}
@@ -2,7 +2,12 @@
 //!
 //! The actual pathfinding calculation is in Grid module. This modules handles the async tasks and the data returned by this tasks.
 
-use bevy::prelude::*;
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, RegisterDiagnostic},
+    prelude::*,
+};
+
+use crate::world::grid::systems::update_hierarchical_graph;
 
 pub mod systems;
 use systems::*;
@@ -12,11 +17,56 @@ pub mod resources;
 use resources::*;
 pub mod states;
 use states::*;
+pub mod events;
+use events::*;
 
 pub struct PathfindingPlugin;
 
 impl Plugin for PathfindingPlugin {
     fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(
+            Self::TASKS_IN_FLIGHT,
+            "pathfinding_tasks",
+            20,
+        ))
+        .register_diagnostic(Diagnostic::new(
+            Self::EXPANSIONS,
+            "pathfinding_expansions",
+            20,
+        ))
+        .register_diagnostic(
+            Diagnostic::new(Self::SEARCH_TIME, "pathfinding_search_time", 20).with_suffix("ms"),
+        );
+
+        app.init_resource::<FlowFields>();
+        app.init_resource::<PathfindingQueue>();
+        app.add_event::<PathStarted>()
+            .add_event::<WaypointReached>()
+            .add_event::<DestinationReached>()
+            .add_event::<PathFailed>();
+
         app.add_systems(Update, handle_pathfinding_tasks);
+        // requests snapshot the graph, so they wait for it to follow the changes of this frame
+        app.add_systems(
+            Update,
+            (repair_stale_paths, dispatch_path_requests)
+                .chain()
+                .after(update_hierarchical_graph),
+        );
+        app.add_systems(Update, update_flow_fields.after(update_hierarchical_graph));
+        app.add_systems(Update, start_flow_field_walks);
+        app.add_systems(Update, update_tile_reservations);
     }
 }
+
+impl PathfindingPlugin {
+    /// Pathfinding tasks running in the frame
+    pub const TASKS_IN_FLIGHT: DiagnosticId =
+        DiagnosticId::from_u128(216042355370911257405286371419405389563);
+    /// Average tiles expanded by the searches finished in the frame
+    pub const EXPANSIONS: DiagnosticId =
+        DiagnosticId::from_u128(86617300384391745120911716151874212945);
+    /// Average time of the searches finished in the frame
+    pub const SEARCH_TIME: DiagnosticId =
+        DiagnosticId::from_u128(157264941702785410592113506328380726610);
+}
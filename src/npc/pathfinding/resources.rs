@@ -1 +1,169 @@
 use bevy::prelude::*;
+use hashbrown::HashMap;
+
+use crate::world::grid::{flow_field::FlowField, resources::PathRequest};
+
+/// Cache of the flow fields of the destinations NPCs are walking to.
+///
+/// The fields are dropped when nobody follows them anymore or when the grid changes.
+#[derive(Resource, Default)]
+pub struct FlowFields {
+    pub fields: HashMap<(i32, i32), FlowField>,
+}
+
+impl FlowFields {
+    pub fn get(&self, goal: (i32, i32)) -> Option<&FlowField> {
+        self.fields.get(&goal)
+    }
+}
+
+/// How urgent a path request is, higher priorities are searched first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PathPriority {
+    /// Idle wandering and other paths nobody is waiting for
+    Low,
+    #[default]
+    Normal,
+    /// Moves ordered by the player
+    High,
+}
+
+/// A path request waiting in the [PathfindingQueue].
+#[derive(Clone, Copy, Debug)]
+pub struct QueuedPath {
+    pub entity: Entity,
+    pub request: PathRequest,
+    pub priority: PathPriority,
+    /// Position in arrival order, used to keep the queue fair between equal priorities
+    order: u64,
+}
+
+/// Central queue of the path requests of every entity.
+///
+/// Each entity has at most one queued request. Requesting the same goal again only refreshes the request,
+/// while requesting a different goal replaces it and cancels the search of the old goal.
+/// Only `max_in_flight` searches run at the same time and at most `max_per_frame` start in a frame, the rest wait
+/// in the queue.
+#[derive(Resource)]
+pub struct PathfindingQueue {
+    pub max_in_flight: usize,
+    pub max_per_frame: usize,
+    requests: Vec<QueuedPath>,
+    next_order: u64,
+}
+
+impl Default for PathfindingQueue {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 16,
+            max_per_frame: 4,
+            requests: Vec::new(),
+            next_order: 0,
+        }
+    }
+}
+
+impl PathfindingQueue {
+    /// Queue a path request for an entity
+    pub fn push(&mut self, entity: Entity, request: PathRequest, priority: PathPriority) {
+        let order = self.next_order;
+        self.next_order += 1;
+
+        match self
+            .requests
+            .iter_mut()
+            .find(|queued| queued.entity == entity)
+        {
+            // Same destination, keep its place in the queue but search from the newest start
+            Some(queued) if queued.request.goal == request.goal => {
+                queued.request = request;
+                queued.priority = queued.priority.max(priority);
+            }
+            Some(queued) => {
+                *queued = QueuedPath {
+                    entity,
+                    request,
+                    priority,
+                    order,
+                };
+            }
+            None => self.requests.push(QueuedPath {
+                entity,
+                request,
+                priority,
+                order,
+            }),
+        }
+    }
+
+    /// Remove and return the most urgent request, the oldest one between equal priorities
+    pub fn pop(&mut self) -> Option<QueuedPath> {
+        let (index, _) = self
+            .requests
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, queued)| (queued.priority, std::cmp::Reverse(queued.order)))?;
+        Some(self.requests.swap_remove(index))
+    }
+
+    /// Return the request queued for an entity
+    pub fn get(&self, entity: Entity) -> Option<&QueuedPath> {
+        self.requests.iter().find(|queued| queued.entity == entity)
+    }
+
+    /// Drop the request queued for an entity
+    pub fn cancel(&mut self, entity: Entity) {
+        self.requests.retain(|queued| queued.entity != entity);
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Return every queued request, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &QueuedPath> {
+        self.requests.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(goal: f32) -> PathRequest {
+        PathRequest::new(Vec3::ZERO, Vec3::new(goal, 0.0, 0.0))
+    }
+
+    #[test]
+    fn pops_by_priority_then_arrival() {
+        let mut queue = PathfindingQueue::default();
+        queue.push(Entity::from_raw(0), request(1.0), PathPriority::Low);
+        queue.push(Entity::from_raw(1), request(1.0), PathPriority::Normal);
+        queue.push(Entity::from_raw(2), request(1.0), PathPriority::High);
+        queue.push(Entity::from_raw(3), request(1.0), PathPriority::Normal);
+
+        let order: Vec<u32> = std::iter::from_fn(|| queue.pop())
+            .map(|queued| queued.entity.index())
+            .collect();
+        assert_eq!(order, vec![2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn one_request_per_entity() {
+        let mut queue = PathfindingQueue::default();
+        let entity = Entity::from_raw(0);
+        queue.push(entity, request(1.0), PathPriority::High);
+        queue.push(entity, request(1.0), PathPriority::Low);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.get(entity).unwrap().priority, PathPriority::High);
+
+        queue.push(entity, request(2.0), PathPriority::Low);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.get(entity).unwrap().request.goal.x, 2.0);
+        assert_eq!(queue.get(entity).unwrap().priority, PathPriority::Low);
+    }
+}
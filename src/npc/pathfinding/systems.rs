@@ -1,24 +1,370 @@
-use bevy::prelude::*;
+use bevy::{diagnostic::Diagnostics, prelude::*};
 use futures_lite::future;
+use hashbrown::HashSet;
 
-use super::components::{Pathfinding, PathfindingTask};
+use crate::{
+    npc::{behavior::states::BehaviorState, components::Npc},
+    world::grid::{
+        events::GridChanged,
+        flow_field::FlowField,
+        hierarchical::HierarchicalGraph,
+        resources::{Grid, GridSnapshot, PathRequest},
+    },
+};
+
+use super::{
+    components::{spawn_optimized_pathfinding_task, Pathfinding, PathfindingTask},
+    events::{PathFailed, PathStarted},
+    resources::{FlowFields, PathfindingQueue},
+    PathfindingPlugin,
+};
 
 /// Get the all the PathfindigTask components and verify if each one has already ended. If succeed atach the returned path to the pathfinding component.
+///
+/// Results of goals the entity no longer wants are dropped, even when the search finished before it was cancelled.
+/// Also records how expensive the finished searches were in the pathfinding diagnostics.
 pub fn handle_pathfinding_tasks(
     mut commands: Commands,
+    queue: Res<PathfindingQueue>,
     mut pathfinding_query: Query<&mut Pathfinding>,
     mut tasks: Query<(Entity, &mut PathfindingTask)>,
+    mut diagnostics: Diagnostics,
+    mut path_started: EventWriter<PathStarted>,
+    mut path_failed: EventWriter<PathFailed>,
 ) {
+    let mut in_flight = 0;
+    let mut expansions = 0;
+    let mut search_time = 0.0;
+    let mut finished = 0;
+
     for (task_entity, mut task) in &mut tasks {
-        if let Some(result) = future::block_on(future::poll_once(&mut task.0)) {
+        in_flight += 1;
+        if let Some(search) = future::block_on(future::poll_once(&mut task.task)) {
             commands.entity(task_entity).remove::<PathfindingTask>();
+            finished += 1;
+            expansions += search.expansions;
+            search_time += search.elapsed.as_secs_f64() * 1000.0;
+
+            // A new goal was requested while searching, its own search is still to come
+            if queue
+                .get(task_entity)
+                .is_some_and(|queued| queued.request.goal != task.request.goal)
+            {
+                continue;
+            }
             if let Ok(mut pathfinding) = pathfinding_query.get_mut(task_entity) {
-                if let Ok(path) = result {
-                    pathfinding.path = Some(path);
-                } else {
-                    error!("No path was found the {:?} entity", task_entity);
+                match search.result {
+                    Ok(path) => {
+                        if let Some(&destination) = path.steps.last() {
+                            path_started.send(PathStarted {
+                                entity: task_entity,
+                                destination,
+                            });
+                        }
+                        pathfinding.path = Some(path);
+                        pathfinding.current_step = 0;
+                        pathfinding.last_error = None;
+                        pathfinding.request = Some(task.request);
+                        pathfinding.priority = task.priority;
+                    }
+                    Err(error) => {
+                        error!(
+                            "No path was found for the {:?} entity: {}",
+                            task_entity, error
+                        );
+                        pathfinding.last_error = Some(error);
+                        path_failed.send(PathFailed {
+                            entity: task_entity,
+                            error,
+                        });
+                    }
                 }
             }
         }
     }
+
+    diagnostics.add_measurement(PathfindingPlugin::TASKS_IN_FLIGHT, || in_flight as f64);
+    if finished > 0 {
+        diagnostics.add_measurement(PathfindingPlugin::EXPANSIONS, || {
+            expansions as f64 / finished as f64
+        });
+        diagnostics.add_measurement(PathfindingPlugin::SEARCH_TIME, || {
+            search_time / finished as f64
+        });
+    }
+}
+
+/// Start the most urgent queued path requests while there is room for more tasks in flight, up to the budget of
+/// the frame.
+///
+/// Tasks searching a goal the entity no longer wants are cancelled first.
+pub fn dispatch_path_requests(
+    mut commands: Commands,
+    mut queue: ResMut<PathfindingQueue>,
+    grid: Res<Grid>,
+    graph: Res<HierarchicalGraph>,
+    mut snapshots: ResMut<GridSnapshot>,
+    entities: Query<(Entity, Option<&PathfindingTask>), With<Pathfinding>>,
+) {
+    // Cancel the searches of goals that are no longer wanted, the rest keep running until they are replaced
+    let mut running = HashSet::new();
+    for (entity, task) in entities.iter() {
+        let Some(task) = task else {
+            continue;
+        };
+        match queue.get(entity) {
+            Some(queued) if queued.request.goal != task.request.goal => {
+                commands.entity(entity).remove::<PathfindingTask>();
+            }
+            _ => {
+                running.insert(entity);
+            }
+        }
+    }
+
+    if queue.is_empty() || running.len() >= queue.max_in_flight {
+        return;
+    }
+    let (grid_snapshot, graph_snapshot) = snapshots.get(&grid, &graph);
+
+    let mut dispatched = 0;
+    while running.len() < queue.max_in_flight && dispatched < queue.max_per_frame {
+        let Some(queued) = queue.pop() else {
+            break;
+        };
+        // The entity may have been despawned while waiting
+        if entities.get(queued.entity).is_err() {
+            continue;
+        }
+
+        spawn_optimized_pathfinding_task(
+            &mut commands,
+            queued.entity,
+            &grid_snapshot,
+            &graph_snapshot,
+            PathRequest {
+                entity: Some(queued.entity),
+                ..queued.request
+            },
+            queued.priority,
+        );
+        running.insert(queued.entity);
+        dispatched += 1;
+    }
+}
+
+/// Search again the paths made stale by changes in the grid.
+///
+/// Only paths whose remaining steps cross a changed tile are searched again, starting from the current position
+/// of the entity. Searches still running used the old grid, so they are restarted as well.
+pub fn repair_stale_paths(
+    mut events: EventReader<GridChanged>,
+    grid: Res<Grid>,
+    mut queue: ResMut<PathfindingQueue>,
+    mut pathfinding_query: Query<(Entity, &Transform, &mut Pathfinding)>,
+    tasks: Query<&PathfindingTask>,
+) {
+    // paths are only repaired when they may be blocked, cheaper ways are picked up by the next request
+    let changed: HashSet<(i32, i32)> = events
+        .iter()
+        .flat_map(|event| event.obstructed.iter().copied())
+        .collect();
+    if changed.is_empty() {
+        return;
+    }
+
+    for (entity, transform, mut pathfinding) in &mut pathfinding_query {
+        let (request, priority) = if let Ok(task) = tasks.get(entity) {
+            (task.request, task.priority)
+        } else if pathfinding.crosses_tiles(&grid, &changed) {
+            let Some(request) = pathfinding.request else {
+                continue;
+            };
+            pathfinding.path = None;
+            pathfinding.current_step = 0;
+            (request, pathfinding.priority)
+        } else {
+            continue;
+        };
+
+        queue.push(
+            entity,
+            PathRequest {
+                start: transform.translation,
+                ..request
+            },
+            priority,
+        );
+    }
+}
+
+/// Flow fields built in a single frame, the others wait for the next frames
+const FLOW_FIELDS_PER_FRAME: usize = 1;
+
+/// Keep a flow field for every destination followed by an entity.
+///
+/// Fields nobody follows are dropped and all fields are rebuilt when walls change.
+pub fn update_flow_fields(
+    grid: Res<Grid>,
+    mut flow_fields: ResMut<FlowFields>,
+    mut events: EventReader<GridChanged>,
+    pathfinding_query: Query<&Pathfinding>,
+) {
+    if events.iter().any(|event| !event.obstructed.is_empty()) {
+        flow_fields.fields.clear();
+    }
+
+    let goals: HashSet<(i32, i32)> = pathfinding_query
+        .iter()
+        .filter_map(|pathfinding| pathfinding.flow_goal)
+        .collect();
+    flow_fields.fields.retain(|goal, _| goals.contains(goal));
+
+    let missing: Vec<(i32, i32)> = goals
+        .into_iter()
+        .filter(|goal| !flow_fields.fields.contains_key(goal))
+        .take(FLOW_FIELDS_PER_FRAME)
+        .collect();
+    for goal in missing {
+        flow_fields
+            .fields
+            .insert(goal, FlowField::build(&grid, goal));
+    }
+}
+
+/// Start walking the NPCs told to follow a flow field.
+///
+/// They walk as soon as the [PathStarted] is handled, and wait in place while their field is built.
+pub fn start_flow_field_walks(
+    grid: Res<Grid>,
+    npcs: Query<(Entity, &Pathfinding, &Npc), Changed<Pathfinding>>,
+    mut path_started: EventWriter<PathStarted>,
+) {
+    for (entity, pathfinding, npc) in &npcs {
+        let Some(goal) = pathfinding.flow_goal else {
+            continue;
+        };
+        if npc.behavior_state != BehaviorState::Walking {
+            path_started.send(PathStarted {
+                entity,
+                destination: grid.coord_to_tile([goal.0, goal.1]),
+            });
+        }
+    }
+}
+
+/// Reserve the tile of the entities that arrived to their destination and release it when they leave.
+///
+/// Entities walking a path or a flow field hold no reservation, so only the ones standing still block others.
+pub fn update_tile_reservations(
+    mut grid: ResMut<Grid>,
+    pathfinding_query: Query<(Entity, &Transform, &Pathfinding, Option<&PathfindingTask>)>,
+    mut removed: RemovedComponents<Pathfinding>,
+) {
+    for entity in removed.iter() {
+        grid.release(entity);
+    }
+
+    for (entity, transform, pathfinding, task) in &pathfinding_query {
+        let coord = grid.world_to_coord(transform.translation);
+        let arrived =
+            task.is_none() && pathfinding.flow_goal.is_none() && !pathfinding.is_walking();
+        let reserved = grid.reserved_tile(entity);
+
+        if arrived && reserved != Some((coord[0], coord[1])) {
+            grid.reserve((coord[0], coord[1]), entity);
+        } else if !arrived && reserved.is_some() {
+            grid.release(entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::npc::pathfinding::resources::PathPriority;
+    use bevy::diagnostic::DiagnosticsPlugin;
+
+    /// Headless app searching the queued paths, the finished searches are handled before new ones are dispatched
+    fn pathfinding_app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, DiagnosticsPlugin))
+            .init_resource::<Grid>()
+            .init_resource::<HierarchicalGraph>()
+            .init_resource::<GridSnapshot>()
+            .init_resource::<PathfindingQueue>()
+            .add_event::<PathStarted>()
+            .add_event::<PathFailed>()
+            .add_systems(
+                Update,
+                (handle_pathfinding_tasks, dispatch_path_requests).chain(),
+            );
+        app
+    }
+
+    #[test]
+    fn only_a_few_searches_start_each_frame() {
+        let mut app = pathfinding_app();
+        for i in 0..10 {
+            let entity = app.world.spawn(Pathfinding::default()).id();
+            app.world.resource_mut::<PathfindingQueue>().push(
+                entity,
+                PathRequest::new(Vec3::ZERO, Vec3::new(i as f32, 0.0, 1.0)),
+                PathPriority::Normal,
+            );
+        }
+
+        app.update();
+
+        let max_per_frame = app.world.resource::<PathfindingQueue>().max_per_frame;
+        let mut tasks = app.world.query::<&PathfindingTask>();
+        assert_eq!(tasks.iter(&app.world).count(), max_per_frame);
+        assert_eq!(
+            app.world.resource::<PathfindingQueue>().len(),
+            10 - max_per_frame
+        );
+    }
+
+    #[test]
+    fn finished_searches_of_replaced_goals_are_dropped() {
+        let mut app = pathfinding_app();
+        let entity = app.world.spawn(Pathfinding::default()).id();
+        let old_goal = Vec3::new(2.0, 0.0, 0.0);
+        let new_goal = Vec3::new(0.0, 0.0, 2.0);
+        app.world.resource_mut::<PathfindingQueue>().push(
+            entity,
+            PathRequest::new(Vec3::ZERO, old_goal),
+            PathPriority::Normal,
+        );
+        app.update();
+        while !app
+            .world
+            .get::<PathfindingTask>(entity)
+            .unwrap()
+            .task
+            .is_finished()
+        {
+            std::thread::yield_now();
+        }
+
+        // The new goal arrives after the old search finished but before it is handled
+        app.world.resource_mut::<PathfindingQueue>().push(
+            entity,
+            PathRequest::new(Vec3::ZERO, new_goal),
+            PathPriority::Normal,
+        );
+        let mut reader = app.world.resource::<Events<PathStarted>>().get_reader();
+        app.update();
+        assert!(app.world.get::<Pathfinding>(entity).unwrap().path.is_none());
+        assert_eq!(reader.iter(app.world.resource()).count(), 0);
+
+        let mut started = 0;
+        for _ in 0..100 {
+            app.update();
+            started += reader.iter(app.world.resource()).count();
+        }
+        let pathfinding = app.world.get::<Pathfinding>(entity).unwrap();
+        assert_eq!(pathfinding.request.unwrap().goal, new_goal);
+        assert_eq!(started, 1);
+    }
 }
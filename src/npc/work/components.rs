@@ -0,0 +1,67 @@
+use bevy::prelude::*;
+
+use crate::npc::components::{Atributtes, Attribute};
+
+/// Highest value of an attribute
+const MAX_ATTRIBUTE: u32 = 100;
+
+/// Work an NPC did so far.
+#[derive(Component, Default)]
+pub struct WorkProgress {
+    /// Fraction of the next unit of output
+    pending: f32,
+    /// Whole units of output produced
+    pub produced: u32,
+}
+
+impl WorkProgress {
+    /// Add some work and return the whole units it finished
+    pub fn accumulate(&mut self, amount: f32) -> u32 {
+        self.pending += amount;
+        let whole = self.pending.trunc();
+        self.pending -= whole;
+        self.produced += whole as u32;
+        whole as u32
+    }
+}
+
+/// Experience an NPC gathered towards the next point of each attribute.
+#[derive(Component, Default)]
+pub struct SkillProgress {
+    experience: [f32; Attribute::ALL.len()],
+}
+
+impl SkillProgress {
+    /// Experience needed to raise an attribute from its value, higher attributes take longer to improve
+    pub fn experience_to_next_point(value: u32) -> f32 {
+        value.max(1) as f32
+    }
+
+    /// Add experience to an attribute and raise it while it reaches the next point, returning the points gained
+    pub fn add_experience(
+        &mut self,
+        atributtes: &mut Atributtes,
+        attribute: Attribute,
+        amount: f32,
+    ) -> u32 {
+        let index = Attribute::ALL
+            .iter()
+            .position(|&other| other == attribute)
+            .unwrap_or_default();
+        let value = atributtes.get_mut(attribute);
+        if *value >= MAX_ATTRIBUTE {
+            return 0;
+        }
+
+        self.experience[index] += amount;
+        let mut points = 0;
+        while *value < MAX_ATTRIBUTE
+            && self.experience[index] >= Self::experience_to_next_point(*value)
+        {
+            self.experience[index] -= Self::experience_to_next_point(*value);
+            *value += 1;
+            points += 1;
+        }
+        points
+    }
+}
@@ -0,0 +1,11 @@
+use bevy::prelude::*;
+
+use super::resources::WorkProduct;
+
+/// Sent every time an NPC finishes whole units of the output of its role.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct WorkProduced {
+    pub entity: Entity,
+    pub product: WorkProduct,
+    pub amount: u32,
+}
@@ -0,0 +1,35 @@
+//! Simulates the work done by the NPCs.
+//!
+//! An NPC working at its desk produces the output of its role at a rate that grows with the attributes the
+//! role relies on, its level, its focus and creativity and the quality of its desk and room. Every unit of
+//! output gives the NPC experience and trains the attributes of its role.
+use bevy::prelude::*;
+
+pub mod systems;
+use systems::*;
+pub mod components;
+use components::*;
+pub mod resources;
+use resources::*;
+pub mod states;
+use states::*;
+pub mod events;
+use events::*;
+
+use super::behavior::systems::advance_clock;
+
+pub struct WorkPlugin;
+
+impl Plugin for WorkPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WorkConfig>()
+            .init_resource::<WorkOutput>();
+        app.add_event::<WorkProduced>();
+        app.add_systems(
+            Update,
+            (simulate_work, count_work_output, gain_work_experience)
+                .chain()
+                .after(advance_clock),
+        );
+    }
+}
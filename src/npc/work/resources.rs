@@ -0,0 +1,269 @@
+use bevy::prelude::*;
+use hashbrown::HashMap;
+
+use crate::{
+    npc::components::{Attribute, Need, Npc, Role},
+    world::{
+        furniture::components::FurnitureKind,
+        rooms::resources::{Room, RoomType},
+    },
+};
+
+/// What the work of a role produces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WorkProduct {
+    Features,
+    Designs,
+    Leads,
+    Clients,
+    Reports,
+    Hires,
+    ResolvedTickets,
+}
+
+impl WorkProduct {
+    /// Return what a role produces, None for NPCs without a role
+    pub fn of(role: Role) -> Option<Self> {
+        match role {
+            Role::Tech => Some(WorkProduct::Features),
+            Role::Design => Some(WorkProduct::Designs),
+            Role::Marketing => Some(WorkProduct::Leads),
+            Role::CustomerAcquisition => Some(WorkProduct::Clients),
+            Role::Finance => Some(WorkProduct::Reports),
+            Role::HumanResources => Some(WorkProduct::Hires),
+            Role::SupportServices => Some(WorkProduct::ResolvedTickets),
+            Role::None => None,
+        }
+    }
+}
+
+/// Rates of the work simulation.
+///
+/// The output rate of an NPC is the base rate of its role multiplied by the factors of its attributes, level,
+/// needs, desk and room. Each factor is 1 for an average NPC at an ordinary desk in a bare open office, the
+/// furniture of the room adds to its factor.
+#[derive(Resource)]
+pub struct WorkConfig {
+    /// Units of output per second of game time
+    pub base_rate: HashMap<Role, f32>,
+    /// Attributes each role relies on and their weights
+    pub role_attributes: HashMap<Role, Vec<(Attribute, f32)>>,
+    /// Value of an attribute that gives a factor of 1
+    pub average_attribute: f32,
+    /// Extra output for each level of the NPC
+    pub level_bonus: f32,
+    /// Share of the needs factor that comes from creativity instead of focus, 0.3 for missing roles
+    pub creativity_weight: HashMap<Role, f32>,
+    /// Needs factor of an NPC with no focus nor creativity left
+    pub exhausted_factor: f32,
+    /// Factor of each room type, missing types count as 1
+    pub room_factor: HashMap<RoomType, f32>,
+    /// Factor of working outside of a valid room
+    pub no_room_factor: f32,
+    /// Extra room factor of each piece of furniture of a kind in the room, like the plants
+    pub furniture_bonus: HashMap<FurnitureKind, f32>,
+    /// Highest extra room factor the furniture of a room can add
+    pub max_furniture_bonus: f32,
+    /// Experience of the NPC for each unit of output
+    pub experience_per_unit: u32,
+    /// Experience of each attribute of the role for each unit of output, multiplied by its weight
+    pub skill_experience_per_unit: f32,
+}
+
+impl Default for WorkConfig {
+    fn default() -> Self {
+        Self {
+            base_rate: HashMap::from([
+                (Role::Tech, 0.002),
+                (Role::Design, 0.003),
+                (Role::Marketing, 0.004),
+                (Role::CustomerAcquisition, 0.002),
+                (Role::Finance, 0.002),
+                (Role::HumanResources, 0.001),
+                (Role::SupportServices, 0.006),
+            ]),
+            role_attributes: HashMap::from([
+                (
+                    Role::Tech,
+                    vec![(Attribute::Logic, 0.7), (Attribute::Practical, 0.3)],
+                ),
+                (
+                    Role::Design,
+                    vec![(Attribute::Artistic, 0.7), (Attribute::Practical, 0.3)],
+                ),
+                (
+                    Role::Marketing,
+                    vec![(Attribute::Linguistic, 0.5), (Attribute::Social, 0.5)],
+                ),
+                (
+                    Role::CustomerAcquisition,
+                    vec![(Attribute::Social, 0.7), (Attribute::Linguistic, 0.3)],
+                ),
+                (
+                    Role::Finance,
+                    vec![(Attribute::Logic, 0.8), (Attribute::Practical, 0.2)],
+                ),
+                (
+                    Role::HumanResources,
+                    vec![(Attribute::Social, 0.6), (Attribute::Linguistic, 0.4)],
+                ),
+                (
+                    Role::SupportServices,
+                    vec![(Attribute::Social, 0.5), (Attribute::Practical, 0.5)],
+                ),
+            ]),
+            average_attribute: 50.0,
+            level_bonus: 0.1,
+            creativity_weight: HashMap::from([
+                (Role::Design, 0.7),
+                (Role::Marketing, 0.6),
+                (Role::Tech, 0.2),
+                (Role::Finance, 0.1),
+            ]),
+            exhausted_factor: 0.25,
+            room_factor: HashMap::from([
+                (RoomType::OpenOffice, 1.0),
+                (RoomType::MeetingRoom, 0.9),
+                (RoomType::ServerRoom, 0.9),
+                (RoomType::Lounge, 0.8),
+                (RoomType::Kitchen, 0.7),
+                (RoomType::Restroom, 0.5),
+            ]),
+            no_room_factor: 0.8,
+            furniture_bonus: HashMap::from([
+                (FurnitureKind::Plant, 0.05),
+                (FurnitureKind::Whiteboard, 0.05),
+                (FurnitureKind::CoffeeMachine, 0.03),
+            ]),
+            max_furniture_bonus: 0.25,
+            experience_per_unit: 10,
+            skill_experience_per_unit: 5.0,
+        }
+    }
+}
+
+impl WorkConfig {
+    /// Return the factor of working in a room from its type and the furniture in it
+    pub fn room_quality(
+        &self,
+        room: Option<&Room>,
+        furniture: &HashMap<FurnitureKind, usize>,
+    ) -> f32 {
+        let Some(room_type) = room
+            .filter(|room| room.is_valid())
+            .and_then(|room| room.room_type)
+        else {
+            return self.no_room_factor;
+        };
+
+        let bonus = furniture
+            .iter()
+            .map(|(kind, &count)| {
+                self.furniture_bonus.get(kind).copied().unwrap_or(0.0) * count as f32
+            })
+            .sum::<f32>()
+            .min(self.max_furniture_bonus);
+        self.room_factor.get(&room_type).copied().unwrap_or(1.0) + bonus
+    }
+
+    /// Return the units of output per second of game time of an NPC.
+    ///
+    /// `desk_quality` is the quality of the furniture the NPC works at and `room_quality` the factor of the
+    /// room it is in, see [WorkConfig::room_quality].
+    pub fn output_rate(&self, npc: &Npc, desk_quality: f32, room_quality: f32) -> f32 {
+        let Some(&base) = self.base_rate.get(&npc.role) else {
+            return 0.0;
+        };
+
+        let attributes = self
+            .role_attributes
+            .get(&npc.role)
+            .map(|weights| {
+                weights
+                    .iter()
+                    .map(|&(attribute, weight)| npc.atributtes.get(attribute) as f32 * weight)
+                    .sum::<f32>()
+                    / self.average_attribute
+            })
+            .unwrap_or(1.0);
+
+        let level = 1.0 + npc.level as f32 * self.level_bonus;
+
+        let creativity = self
+            .creativity_weight
+            .get(&npc.role)
+            .copied()
+            .unwrap_or(0.3);
+        let readiness = (npc.needs.get(Need::Focus) as f32 * (1.0 - creativity)
+            + npc.needs.get(Need::Creativity) as f32 * creativity)
+            / 100.0;
+        let needs = self.exhausted_factor + (1.0 - self.exhausted_factor) * readiness;
+
+        base * attributes * level * needs * desk_quality * room_quality
+    }
+}
+
+/// Total output of the office.
+#[derive(Resource, Default)]
+pub struct WorkOutput {
+    totals: HashMap<WorkProduct, u32>,
+}
+
+impl WorkOutput {
+    pub fn add(&mut self, product: WorkProduct, amount: u32) {
+        *self.totals.entry(product).or_default() += amount;
+    }
+
+    pub fn get(&self, product: WorkProduct) -> u32 {
+        self.totals.get(&product).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_grows_with_attributes_level_and_needs() {
+        let config = WorkConfig::default();
+        let mut npc = Npc::default();
+        npc.role = Role::Tech;
+        let base = config.output_rate(&npc, 1.0, 1.0);
+
+        let mut skilled = Npc::default();
+        skilled.role = Role::Tech;
+        skilled.atributtes.logic = 90;
+        skilled.level = 3;
+        assert!(config.output_rate(&skilled, 1.0, 1.0) > base);
+
+        let mut tired = Npc::default();
+        tired.role = Role::Tech;
+        tired.needs.focus = 10;
+        assert!(config.output_rate(&tired, 1.0, 1.0) < base);
+
+        assert!(config.output_rate(&npc, 1.5, 1.0) > base);
+        assert_eq!(config.output_rate(&Npc::default(), 1.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn furniture_makes_the_room_better_up_to_a_limit() {
+        let config = WorkConfig::default();
+        let office = Room {
+            id: 1,
+            tiles: Default::default(),
+            area: 4.0,
+            room_type: Some(RoomType::OpenOffice),
+            issues: vec![],
+        };
+        let bare = config.room_quality(Some(&office), &HashMap::new());
+        assert_eq!(bare, 1.0);
+
+        let plants = |count| HashMap::from([(FurnitureKind::Plant, count)]);
+        assert!(config.room_quality(Some(&office), &plants(2)) > bare);
+        assert_eq!(
+            config.room_quality(Some(&office), &plants(100)),
+            bare + config.max_furniture_bonus
+        );
+        assert_eq!(config.room_quality(None, &plants(2)), config.no_room_factor);
+    }
+}
@@ -0,0 +1,231 @@
+use bevy::prelude::*;
+use hashbrown::HashMap;
+
+use crate::{
+    npc::{
+        behavior::{
+            components::{ActionTarget, Brain},
+            resources::GameClock,
+            states::BehaviorState,
+            utility::Action,
+        },
+        components::Npc,
+    },
+    world::{
+        furniture::components::Furniture,
+        grid::resources::Grid,
+        physics::components::BoxCollider,
+        rooms::resources::{furniture_in_rooms, Rooms},
+    },
+};
+
+use super::{
+    components::{SkillProgress, WorkProgress},
+    events::WorkProduced,
+    resources::{WorkConfig, WorkOutput, WorkProduct},
+};
+
+/// Make every NPC that is working at its desk produce the output of its role.
+///
+/// Sends a [WorkProduced] every time an NPC finishes whole units of output.
+pub fn simulate_work(
+    clock: Res<GameClock>,
+    config: Res<WorkConfig>,
+    grid: Res<Grid>,
+    rooms: Res<Rooms>,
+    furniture_query: Query<(&Furniture, &BoxCollider)>,
+    mut npcs: Query<(Entity, &Transform, &Npc, &Brain, &mut WorkProgress)>,
+    mut work_produced: EventWriter<WorkProduced>,
+) {
+    let delta = clock.delta;
+    if delta <= 0.0 {
+        return;
+    }
+    let furniture = furniture_in_rooms(
+        &grid,
+        furniture_query
+            .iter()
+            .map(|(piece, collider)| (piece.kind, collider)),
+    );
+    let no_furniture = HashMap::new();
+
+    for (entity, transform, npc, brain, mut progress) in &mut npcs {
+        if npc.behavior_state != BehaviorState::Working || brain.action != Some(Action::Work) {
+            continue;
+        }
+        let Some(product) = WorkProduct::of(npc.role) else {
+            continue;
+        };
+
+        let desk_quality = match brain.target {
+            Some(ActionTarget::Furniture { entity: desk, .. }) => furniture_query
+                .get(desk)
+                .map(|(desk, _)| desk.quality)
+                .unwrap_or(1.0),
+            _ => 1.0,
+        };
+        let coord = grid.world_to_coord(transform.translation);
+        let room = rooms.room_at(&grid, (coord[0], coord[1]));
+        let room_quality = config.room_quality(
+            room,
+            room.and_then(|room| furniture.get(&room.id))
+                .unwrap_or(&no_furniture),
+        );
+
+        let amount =
+            progress.accumulate(config.output_rate(npc, desk_quality, room_quality) * delta);
+        if amount > 0 {
+            work_produced.send(WorkProduced {
+                entity,
+                product,
+                amount,
+            });
+        }
+    }
+}
+
+/// Add the output of the NPCs to the totals of the office
+pub fn count_work_output(
+    mut output: ResMut<WorkOutput>,
+    mut work_produced: EventReader<WorkProduced>,
+) {
+    for event in work_produced.iter() {
+        output.add(event.product, event.amount);
+    }
+}
+
+/// Give experience to the NPCs for the work they finished and train the attributes their role relies on
+pub fn gain_work_experience(
+    config: Res<WorkConfig>,
+    mut npcs: Query<(&mut Npc, &mut SkillProgress)>,
+    mut work_produced: EventReader<WorkProduced>,
+) {
+    for event in work_produced.iter() {
+        let Ok((mut npc, mut skills)) = npcs.get_mut(event.entity) else {
+            continue;
+        };
+
+        npc.add_experience(config.experience_per_unit * event.amount);
+
+        let role = npc.role;
+        for &(attribute, weight) in config.role_attributes.get(&role).into_iter().flatten() {
+            let amount = config.skill_experience_per_unit * weight * event.amount as f32;
+            skills.add_experience(&mut npc.atributtes, attribute, amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        npc::{behavior::systems::advance_clock, components::Role},
+        world::furniture::components::FurnitureKind,
+    };
+    use bevy::time::TimeUpdateStrategy;
+    use std::time::Duration;
+
+    #[test]
+    fn only_npcs_working_at_their_desk_produce() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs(10)))
+            .init_resource::<GameClock>()
+            .init_resource::<WorkConfig>()
+            .init_resource::<WorkOutput>()
+            .init_resource::<Grid>()
+            .init_resource::<Rooms>()
+            .add_event::<WorkProduced>()
+            .add_systems(
+                Update,
+                (advance_clock, simulate_work, count_work_output).chain(),
+            );
+
+        let mut desk = |quality: f32| {
+            app.world
+                .spawn((
+                    Furniture {
+                        quality,
+                        ..Furniture::new(FurnitureKind::Desk)
+                    },
+                    BoxCollider::new(Vec3::ZERO, Quat::IDENTITY, Vec3::splat(0.1)),
+                ))
+                .id()
+        };
+        let ordinary_desk = desk(1.0);
+        let good_desk = desk(2.0);
+
+        let mut spawn = |state: BehaviorState, action: Action, desk: Entity| {
+            let mut npc = Npc::default();
+            npc.role = Role::SupportServices;
+            npc.behavior_state = state;
+            let brain = Brain {
+                action: Some(action),
+                target: Some(ActionTarget::Furniture {
+                    entity: desk,
+                    slot: 0,
+                }),
+                ..Default::default()
+            };
+            app.world
+                .spawn((Transform::default(), npc, brain, WorkProgress::default()))
+                .id()
+        };
+        let worker = spawn(BehaviorState::Working, Action::Work, ordinary_desk);
+        let lucky_worker = spawn(BehaviorState::Working, Action::Work, good_desk);
+        let walking = spawn(BehaviorState::Walking, Action::Work, ordinary_desk);
+        let eating = spawn(BehaviorState::Working, Action::Eat, ordinary_desk);
+
+        for _ in 0..100 {
+            app.update();
+        }
+
+        let produced = |entity| app.world.get::<WorkProgress>(entity).unwrap().produced;
+        assert!(produced(worker) > 0);
+        // the better desk doubles the output
+        assert!(produced(lucky_worker).abs_diff(2 * produced(worker)) <= 1);
+        assert_eq!(produced(walking), 0);
+        assert_eq!(produced(eating), 0);
+        assert_eq!(
+            app.world
+                .resource::<WorkOutput>()
+                .get(WorkProduct::ResolvedTickets),
+            produced(worker) + produced(lucky_worker)
+        );
+    }
+
+    #[test]
+    fn work_trains_the_attributes_of_the_role() {
+        let mut app = App::new();
+        app.init_resource::<WorkConfig>()
+            .add_event::<WorkProduced>()
+            .add_systems(Update, gain_work_experience);
+
+        let mut spawn = |role: Role| {
+            let mut npc = Npc::default();
+            npc.role = role;
+            app.world.spawn((npc, SkillProgress::default())).id()
+        };
+        let developer = spawn(Role::Tech);
+        let support = spawn(Role::SupportServices);
+
+        for (entity, product) in [
+            (developer, WorkProduct::Features),
+            (support, WorkProduct::ResolvedTickets),
+        ] {
+            app.world.send_event(WorkProduced {
+                entity,
+                product,
+                amount: 40,
+            });
+        }
+        app.update();
+
+        let npc = |entity| app.world.get::<Npc>(entity).unwrap();
+        assert_eq!(npc(developer).level, 1);
+        assert!(npc(developer).atributtes.logic > 50);
+        assert_eq!(npc(developer).atributtes.social, 50);
+        assert!(npc(support).atributtes.social > 50);
+        assert_eq!(npc(support).atributtes.logic, 50);
+    }
+}
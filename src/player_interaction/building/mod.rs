@@ -18,14 +18,20 @@ pub struct BuildingPlugin;
 impl Plugin for BuildingPlugin {
     fn build(&self, app: &mut App) {
         app.add_state::<BuildingState>();
+        app.init_resource::<FurnitureToolData>();
 
         app.add_systems(Update, handle_states);
+        app.add_systems(Update, handle_room_designation);
         app.add_systems(Update, handle_wall.run_if(in_state(BuildingState::Wall)));
         app.add_systems(
             Update,
             handle_window.run_if(in_state(BuildingState::Window)),
         );
         app.add_systems(Update, handle_door.run_if(in_state(BuildingState::Door)));
+        app.add_systems(
+            Update,
+            handle_furniture.run_if(in_state(BuildingState::Furniture)),
+        );
         app.add_systems(
             Update,
             handle_pillar.run_if(in_state(BuildingState::Pillar)),
@@ -1 +1,28 @@
 use bevy::prelude::*;
+
+use crate::world::furniture::components::FurnitureKind;
+
+/// Holds the kind of furniture placed while in the furniture building state.
+#[derive(Resource)]
+pub struct FurnitureToolData {
+    pub kind: FurnitureKind,
+}
+
+impl Default for FurnitureToolData {
+    fn default() -> Self {
+        FurnitureToolData {
+            kind: FurnitureKind::Desk,
+        }
+    }
+}
+
+impl FurnitureToolData {
+    /// Select the next kind of furniture, going back to the first one after the last
+    pub fn next_kind(&mut self) {
+        let index = FurnitureKind::ALL
+            .iter()
+            .position(|&kind| kind == self.kind)
+            .unwrap_or(0);
+        self.kind = FurnitureKind::ALL[(index + 1) % FurnitureKind::ALL.len()];
+    }
+}
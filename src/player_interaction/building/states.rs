@@ -6,6 +6,7 @@ pub enum BuildingState {
     Window,
     Pillar,
     Door,
+    Furniture,
     Destroy,
     None,
 }
@@ -3,21 +3,33 @@ use bevy::prelude::*;
 use crate::{
     asset_manager::resources::AssetsLoaded,
     player_interaction::{picking::resources::PickingData, selection::resources::ObjectToolData},
-    world::{grid::resources::Grid, physics::components::BoxCollider},
+    world::{
+        furniture::components::Furniture,
+        grid::resources::Grid,
+        physics::components::BoxCollider,
+        rooms::{
+            components::Door,
+            resources::{RoomType, Rooms},
+        },
+    },
 };
 
-use super::{components::Building, states::BuildingState};
+use super::{components::Building, resources::FurnitureToolData, states::BuildingState};
 
 const WALL_KEY: KeyCode = KeyCode::F1;
 const PILLAR_KEY: KeyCode = KeyCode::F2;
 const WINDOW_KEY: KeyCode = KeyCode::F3;
 const DOOR_KEY: KeyCode = KeyCode::F4;
 const DESTROY_KEY: KeyCode = KeyCode::F5;
+const ROOM_TYPE_KEY: KeyCode = KeyCode::F6;
+const FURNITURE_KEY: KeyCode = KeyCode::F7;
 
 pub fn handle_states(
     keys: Res<Input<KeyCode>>,
+    current_state: Res<State<BuildingState>>,
     mut building_state: ResMut<NextState<BuildingState>>,
     mut object_tool_data: ResMut<ObjectToolData>,
+    mut furniture_tool_data: ResMut<FurnitureToolData>,
     mut commands: Commands,
 ) {
     if keys.just_pressed(WALL_KEY) {
@@ -36,6 +48,15 @@ pub fn handle_states(
         object_tool_data.delete_entity_in_tool(&mut commands);
         building_state.set(BuildingState::Door);
     }
+    if keys.just_pressed(FURNITURE_KEY) {
+        object_tool_data.delete_entity_in_tool(&mut commands);
+        // pressing it again while placing furniture selects the next kind
+        if *current_state.get() == BuildingState::Furniture {
+            furniture_tool_data.next_kind();
+        }
+        building_state.set(BuildingState::Furniture);
+        info!("Placing {:?}", furniture_tool_data.kind);
+    }
     if keys.pressed(DESTROY_KEY) {
         object_tool_data.delete_entity_in_tool(&mut commands);
         building_state.set(BuildingState::Destroy);
@@ -48,6 +69,7 @@ pub fn spawn_asset(
     mut object_tool_data: ResMut<ObjectToolData>,
     mut picking: Res<PickingData>,
     collider_scale: Vec3,
+    components: impl Bundle,
 ) {
     let hit_point = picking.get_hit_in_ground();
     let translation: Vec3 = match object_tool_data.grid_size {
@@ -67,6 +89,7 @@ pub fn spawn_asset(
                 ..Default::default()
             }),
             Building {},
+            components,
         ))
         .insert(Name::from("building".to_string()))
         .id();
@@ -112,6 +135,7 @@ pub fn handle_wall(
             object_tool_data,
             picking,
             Vec3::new(0.2, 1.7, 1.0),
+            (),
         )
     }
 }
@@ -131,6 +155,7 @@ pub fn handle_window(
             object_tool_data,
             picking,
             Vec3::new(0.2, 1., 1.0),
+            (),
         )
     }
 }
@@ -150,6 +175,7 @@ pub fn handle_pillar(
             object_tool_data,
             picking,
             Vec3::new(0.2, 1.7, 0.2),
+            (),
         )
     }
 }
@@ -169,6 +195,65 @@ pub fn handle_door(
             object_tool_data,
             picking,
             Vec3::new(0.2, 1., 1.0),
+            Door {},
         )
     }
 }
+
+/// Furniture has no models yet, each piece is drawn as a box of the size of its collider
+pub fn handle_furniture(
+    commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    object_tool_data: ResMut<ObjectToolData>,
+    picking: Res<PickingData>,
+    furniture_tool_data: Res<FurnitureToolData>,
+) {
+    let kind = furniture_tool_data.kind;
+
+    if object_tool_data.entity.is_none() {
+        let size = kind.size();
+        let mesh = meshes.add(Mesh::from(shape::Box::new(size.x, size.y, size.z)));
+        spawn_asset(
+            commands,
+            Handle::default(),
+            object_tool_data,
+            picking,
+            size,
+            (
+                Furniture::new(kind),
+                mesh,
+                materials.add(kind.color().into()),
+            ),
+        )
+    }
+}
+
+/// Cycle the type of the room under the cursor between every [RoomType] and no type at all
+pub fn handle_room_designation(
+    keys: Res<Input<KeyCode>>,
+    picking: Res<PickingData>,
+    grid: Res<Grid>,
+    mut rooms: ResMut<Rooms>,
+) {
+    if !keys.just_pressed(ROOM_TYPE_KEY) {
+        return;
+    }
+
+    let coord = grid.world_to_coord(picking.get_hit_in_ground());
+    let Some(room) = rooms.room_at(&grid, (coord[0], coord[1])) else {
+        return;
+    };
+
+    let next = match room.room_type {
+        None => Some(RoomType::ALL[0]),
+        Some(room_type) => RoomType::ALL
+            .iter()
+            .skip_while(|&&other| other != room_type)
+            .nth(1)
+            .copied(),
+    };
+    let id = room.id;
+    rooms.designate(id, next);
+    info!("Room {} designated as {:?}", id, next);
+}
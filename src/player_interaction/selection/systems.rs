@@ -2,12 +2,19 @@ use bevy::prelude::*;
 use bevy_inspector_egui::egui::epaint::tessellator::path;
 
 use crate::{
-    npc::pathfinding::components::{spawn_optimized_pathfinding_task, Pathfinding},
+    npc::pathfinding::{
+        components::Pathfinding,
+        resources::{PathPriority, PathfindingQueue},
+    },
     player_interaction::picking::resources::PickingData,
     spawner::npc::NpcSpawner,
     world::{
-        grid::{self, resources::Grid},
+        grid::{
+            self,
+            resources::{Grid, PathRequest},
+        },
         physics::components::{BoxCollider, LerpMovement},
+        rooms::components::Door,
     },
 };
 
@@ -92,14 +99,19 @@ pub fn handle_can_place_state(
 pub fn handle_entities(
     mut object_tool_data: ResMut<ObjectToolData>,
     mut grid: ResMut<Grid>,
-    query_entity: Query<(&BoxCollider, &LerpMovement), With<BoxCollider>>,
+    query_entity: Query<(&BoxCollider, &LerpMovement, Option<&Door>), With<BoxCollider>>,
     mut commands: Commands,
 ) {
     // Mark tiles for entities that are to be placed
     for &entity in &object_tool_data.entities_to_place {
-        if let Ok((collider, lerp_movement)) = query_entity.get(entity) {
+        if let Ok((collider, lerp_movement, door)) = query_entity.get(entity) {
             if lerp_movement.target_translation.is_none() {
-                grid.mark_tiles_from_collider(collider);
+                // doors close the rooms but the NPCs can walk through them
+                if door.is_some() {
+                    grid.mark_door_from_collider(collider);
+                } else {
+                    grid.mark_tiles_from_collider(collider);
+                }
             }
         }
     }
@@ -109,7 +121,7 @@ pub fn handle_entities(
         .entities_to_place
         .iter()
         .filter(|&&entity| {
-            if let Ok((_, lerp_movement)) = query_entity.get(entity) {
+            if let Ok((_, lerp_movement, _)) = query_entity.get(entity) {
                 lerp_movement.target_translation.is_some()
             } else {
                 true
@@ -121,8 +133,12 @@ pub fn handle_entities(
 
     // Handle entities that are to be removed
     for &entity in &object_tool_data.entities_to_remove {
-        if let Ok((collider, _)) = query_entity.get(entity) {
-            grid.unmark_tiles_from_collider(collider);
+        if let Ok((collider, _, door)) = query_entity.get(entity) {
+            if door.is_some() {
+                grid.unmark_door_from_collider(collider);
+            } else {
+                grid.unmark_tiles_from_collider(collider);
+            }
         }
         commands.entity(entity).despawn_recursive();
     }
@@ -130,27 +146,32 @@ pub fn handle_entities(
 }
 
 pub fn show_path(
-    query_entity: Query<Entity, With<Pathfinding>>,
     keyboard_input: Res<Input<KeyCode>>,
     mut commands: Commands,
     grid: Res<Grid>,
+    mut queue: ResMut<PathfindingQueue>,
     mut gizmos: Gizmos,
-    pathfinding_query: Query<&Pathfinding>,
+    mut pathfinding_query: Query<(Entity, &mut Pathfinding)>,
 ) {
+    if keyboard_input.just_pressed(KeyCode::G) {
+        let goal = grid.world_to_coord(Vec3::new(5.0, 0.0, 5.0));
+        for (_, mut pathfinding) in pathfinding_query.iter_mut() {
+            pathfinding.follow_flow_field((goal[0], goal[1]));
+        }
+    }
+
     if keyboard_input.just_pressed(KeyCode::F) {
         commands.add(NpcSpawner {});
-        if let Ok(entity) = query_entity.get_single() {
-            spawn_optimized_pathfinding_task(
-                &mut commands,
+        if let Ok((entity, _)) = pathfinding_query.get_single() {
+            queue.push(
                 entity,
-                &grid,
-                Vec3::new(0.0, 0.0, 0.0),
-                Vec3::new(5.0, 0.0, 5.0),
+                PathRequest::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(5.0, 0.0, 5.0)),
+                PathPriority::High,
             );
         }
     }
 
-    for (pathfinding) in pathfinding_query.iter() {
+    for (_, pathfinding) in pathfinding_query.iter() {
         if let Some(path) = &pathfinding.path {
             for i in 0..path.steps.len() {
                 if i == path.steps.len() - 1 {
@@ -1,6 +1,12 @@
 use crate::{
     asset_manager::resources::AssetsLoaded,
-    npc::{components::Npc, pathfinding::components::Pathfinding},
+    npc::{
+        behavior::components::{BehaviorTreeState, Brain},
+        components::Npc,
+        needs::components::NeedsProgress,
+        pathfinding::components::Pathfinding,
+        work::components::{SkillProgress, WorkProgress},
+    },
 };
 use bevy::{ecs::system::Command, prelude::*};
 
@@ -24,6 +30,11 @@ impl Command for NpcSpawner {
                 },
                 Npc::default(),
                 Pathfinding::default(),
+                NeedsProgress::default(),
+                WorkProgress::default(),
+                SkillProgress::default(),
+                Brain::default(),
+                BehaviorTreeState::new("office_day"),
                 Name::new("npc".to_string()),
             );
 
@@ -0,0 +1,212 @@
+use bevy::prelude::*;
+
+use crate::npc::components::{Attribute, Need};
+
+/// Kinds of furniture that can be built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FurnitureKind {
+    Desk,
+    Chair,
+    Whiteboard,
+    CoffeeMachine,
+    Server,
+    Plant,
+}
+
+impl FurnitureKind {
+    pub const ALL: [FurnitureKind; 6] = [
+        FurnitureKind::Desk,
+        FurnitureKind::Chair,
+        FurnitureKind::Whiteboard,
+        FurnitureKind::CoffeeMachine,
+        FurnitureKind::Server,
+        FurnitureKind::Plant,
+    ];
+
+    /// Color of the box drawn in place of the piece until the kind has its own model
+    pub fn color(&self) -> Color {
+        match self {
+            FurnitureKind::Desk => Color::rgb(0.55, 0.4, 0.25),
+            FurnitureKind::Chair => Color::rgb(0.3, 0.3, 0.35),
+            FurnitureKind::Whiteboard => Color::rgb(0.95, 0.95, 0.95),
+            FurnitureKind::CoffeeMachine => Color::rgb(0.2, 0.15, 0.1),
+            FurnitureKind::Server => Color::rgb(0.1, 0.1, 0.1),
+            FurnitureKind::Plant => Color::rgb(0.2, 0.6, 0.2),
+        }
+    }
+
+    /// Scale of the collider of the kind
+    pub fn size(&self) -> Vec3 {
+        match self {
+            FurnitureKind::Desk => Vec3::new(0.6, 0.8, 0.4),
+            FurnitureKind::Chair => Vec3::new(0.2, 0.9, 0.2),
+            FurnitureKind::Whiteboard => Vec3::new(0.8, 1.5, 0.2),
+            FurnitureKind::CoffeeMachine => Vec3::new(0.2, 1.0, 0.2),
+            FurnitureKind::Server => Vec3::new(0.4, 1.8, 0.4),
+            FurnitureKind::Plant => Vec3::new(0.2, 0.8, 0.2),
+        }
+    }
+}
+
+/// Where an NPC stands to use a piece of furniture, relative to the furniture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InteractionSlot {
+    pub offset: Vec3,
+    /// Direction the NPC faces while using the furniture
+    pub facing: Vec3,
+}
+
+impl InteractionSlot {
+    /// A slot at `distance` in front of the furniture, facing it
+    fn in_front(x: f32, distance: f32) -> Self {
+        Self {
+            offset: Vec3::new(x, 0.0, distance),
+            facing: Vec3::NEG_Z,
+        }
+    }
+}
+
+/// Component to attach to any piece of furniture
+#[derive(Component, Clone, Debug)]
+pub struct Furniture {
+    pub kind: FurnitureKind,
+    pub slots: Vec<InteractionSlot>,
+    /// How many NPCs can use it at the same time
+    pub capacity: usize,
+    /// Needs replenished per second of game time of use
+    pub needs: Vec<(Need, f32)>,
+    /// Skills trained or used while working with it
+    pub skills: Vec<Attribute>,
+    /// NPCs using it and the slot each one took
+    pub users: Vec<(Entity, usize)>,
+    /// How much it helps the work done at it, 1 for an ordinary piece
+    pub quality: f32,
+}
+
+impl Furniture {
+    pub fn new(kind: FurnitureKind) -> Self {
+        let (slots, needs, skills) = match kind {
+            FurnitureKind::Desk => (
+                vec![InteractionSlot::in_front(0.0, 0.4)],
+                vec![(Need::Focus, 0.02)],
+                vec![Attribute::Logic, Attribute::Linguistic],
+            ),
+            FurnitureKind::Chair => (
+                // the chair takes its own tile, so the NPC rests next to it
+                vec![InteractionSlot::in_front(0.0, 0.3)],
+                vec![(Need::Energy, 0.05)],
+                vec![],
+            ),
+            FurnitureKind::Whiteboard => (
+                vec![
+                    InteractionSlot::in_front(-0.3, 0.4),
+                    InteractionSlot::in_front(0.0, 0.4),
+                    InteractionSlot::in_front(0.3, 0.4),
+                ],
+                vec![(Need::Creativity, 0.1)],
+                vec![Attribute::Social, Attribute::Artistic],
+            ),
+            FurnitureKind::CoffeeMachine => (
+                vec![InteractionSlot::in_front(0.0, 0.3)],
+                vec![(Need::Thirst, 0.5), (Need::Energy, 0.1)],
+                vec![],
+            ),
+            FurnitureKind::Server => (
+                vec![InteractionSlot::in_front(0.0, 0.4)],
+                vec![],
+                vec![Attribute::Logic, Attribute::Practical],
+            ),
+            // plants only make the room nicer, nobody uses them
+            FurnitureKind::Plant => (vec![], vec![], vec![]),
+        };
+
+        Self {
+            kind,
+            capacity: slots.len(),
+            slots,
+            needs,
+            skills,
+            users: Vec::new(),
+            quality: 1.0,
+        }
+    }
+
+    /// Return if another NPC can start using it
+    pub fn is_available(&self) -> bool {
+        self.users.len() < self.capacity.min(self.slots.len())
+    }
+
+    /// Take a free slot for an NPC, returns the slot or None when it is full
+    pub fn take_slot(&mut self, entity: Entity) -> Option<usize> {
+        if let Some(&(_, slot)) = self.users.iter().find(|(user, _)| *user == entity) {
+            return Some(slot);
+        }
+        if !self.is_available() {
+            return None;
+        }
+
+        let slot =
+            (0..self.slots.len()).find(|&slot| self.users.iter().all(|&(_, used)| used != slot))?;
+        self.users.push((entity, slot));
+        Some(slot)
+    }
+
+    /// Free the slot taken by an NPC
+    pub fn release_slot(&mut self, entity: Entity) {
+        self.users.retain(|&(user, _)| user != entity);
+    }
+
+    /// Return the world position and facing direction of a slot
+    pub fn slot_world(&self, transform: &Transform, slot: usize) -> Option<(Vec3, Vec3)> {
+        let slot = self.slots.get(slot)?;
+        Some((
+            transform.transform_point(slot.offset),
+            transform.rotation * slot.facing,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_taken_up_to_the_capacity() {
+        let mut coffee_machine = Furniture::new(FurnitureKind::CoffeeMachine);
+        let (a, b) = (Entity::from_raw(0), Entity::from_raw(1));
+
+        assert_eq!(coffee_machine.take_slot(a), Some(0));
+        assert_eq!(coffee_machine.take_slot(a), Some(0));
+        assert_eq!(coffee_machine.take_slot(b), None);
+
+        coffee_machine.release_slot(a);
+        assert_eq!(coffee_machine.take_slot(b), Some(0));
+    }
+
+    #[test]
+    fn slots_turn_with_the_furniture() {
+        let desk = Furniture::new(FurnitureKind::Desk);
+        let transform = Transform::from_xyz(1.0, 0.0, 1.0)
+            .with_rotation(Quat::from_rotation_y(90.0_f32.to_radians()));
+
+        let (position, facing) = desk.slot_world(&transform, 0).unwrap();
+        assert!(position.distance(Vec3::new(1.4, 0.0, 1.0)) < 1e-5);
+        assert!(facing.distance(Vec3::NEG_X) < 1e-5);
+    }
+
+    #[test]
+    fn slots_are_outside_the_furniture() {
+        for kind in FurnitureKind::ALL {
+            let furniture = Furniture::new(kind);
+            let half_size = kind.size() / 2.0;
+            for slot in &furniture.slots {
+                assert!(
+                    slot.offset.x.abs() > half_size.x || slot.offset.z.abs() > half_size.z,
+                    "{kind:?} has a slot inside it"
+                );
+            }
+            // furniture that helps a need has to be reachable to be used
+            assert!(furniture.needs.is_empty() || !furniture.slots.is_empty());
+        }
+    }
+}
@@ -0,0 +1,21 @@
+//! Furniture NPCs interact with.
+//!
+//! Each piece has interaction slots where NPCs stand to use it, a capacity and the needs and skills it affects.
+use bevy::prelude::*;
+
+pub mod systems;
+use systems::*;
+pub mod components;
+use components::*;
+pub mod resources;
+use resources::*;
+pub mod states;
+use states::*;
+
+pub struct FurniturePlugin;
+
+impl Plugin for FurniturePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, show_interaction_slots);
+    }
+}
@@ -0,0 +1 @@
+use bevy::prelude::*;
@@ -0,0 +1,24 @@
+use bevy::prelude::*;
+
+use super::components::Furniture;
+
+const SHOW_INTERACTION_SLOTS: bool = true;
+
+/// Draw where NPCs stand to use each piece of furniture and the direction they face
+pub fn show_interaction_slots(
+    mut gizmos: Gizmos,
+    furniture_query: Query<(&Furniture, &Transform)>,
+) {
+    if !SHOW_INTERACTION_SLOTS {
+        return;
+    }
+    for (furniture, transform) in furniture_query.iter() {
+        for slot in 0..furniture.slots.len() {
+            let Some((position, facing)) = furniture.slot_world(transform, slot) else {
+                continue;
+            };
+            gizmos.circle(position, Vec3::Y, 0.05, Color::YELLOW);
+            gizmos.line(position, position + facing * 0.1, Color::YELLOW);
+        }
+    }
+}
@@ -0,0 +1,13 @@
+use bevy::prelude::*;
+use hashbrown::HashSet;
+
+/// Sent once per frame with the tiles changed by placing or destroying walls and doors, changing floors or reserving tiles.
+#[derive(Event, Clone, Debug)]
+pub struct GridChanged {
+    /// Tiles whose obstruction or step cost changed
+    pub tiles: HashSet<(i32, i32)>,
+    /// Tiles whose obstruction changed, all of them are also in `tiles`
+    pub obstructed: HashSet<(i32, i32)>,
+    /// Tiles that gained or lost a door
+    pub doors: HashSet<(i32, i32)>,
+}
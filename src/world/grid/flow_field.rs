@@ -0,0 +1,97 @@
+//! Flow fields ( Dijkstra maps ) for crowds walking to the same destination.
+//!
+//! A flow field holds the cost from every reachable tile to a goal. Any entity can follow it by walking to
+//! the neighbor tile with the lowest cost, so one field serves all the NPCs going to the same place.
+
+use bevy::prelude::*;
+use hashbrown::HashMap;
+use pathfinding::directed::dijkstra::dijkstra_all;
+
+use super::resources::Grid;
+
+/// Cost to reach a goal tile from every tile of the grid.
+#[derive(Clone, Debug)]
+pub struct FlowField {
+    pub goal: (i32, i32),
+    costs: HashMap<(i32, i32), u32>,
+}
+
+impl FlowField {
+    /// Spread the costs from the goal to all the reachable tiles
+    pub fn build(grid: &Grid, goal: (i32, i32)) -> Self {
+        let mut costs: HashMap<(i32, i32), u32> = HashMap::new();
+        if !grid.is_obstructed(goal) {
+            costs.insert(goal, 0);
+        }
+
+        // walking from a neighbor into the tile costs the step into the tile, not into the neighbor
+        let successors = |&tile: &(i32, i32)| {
+            let Some(data) = grid.get_tile(tile) else {
+                return vec![];
+            };
+            grid.walkable_neighbors(tile)
+                .into_iter()
+                .map(|(neighbor, _)| {
+                    let diagonal = neighbor.0 != tile.0 && neighbor.1 != tile.1;
+                    (neighbor, grid.cost_model.step_cost(&data, diagonal))
+                })
+                .collect::<Vec<_>>()
+        };
+        if costs.contains_key(&goal) {
+            for (tile, (_, cost)) in dijkstra_all(&goal, successors) {
+                costs.insert(tile, cost);
+            }
+        }
+
+        Self { goal, costs }
+    }
+
+    /// Return the cost to reach the goal, or None when it can not be reached from the tile
+    pub fn cost(&self, coord: (i32, i32)) -> Option<u32> {
+        self.costs.get(&coord).copied()
+    }
+
+    /// Return the neighbor tile to walk to from a tile, or None in the goal or in unreachable tiles
+    pub fn next_tile(&self, grid: &Grid, coord: (i32, i32)) -> Option<(i32, i32)> {
+        let current = self.cost(coord)?;
+        grid.walkable_neighbors(coord)
+            .into_iter()
+            .filter_map(|(neighbor, _)| self.cost(neighbor).map(|cost| (cost, neighbor)))
+            .filter(|&(cost, _)| cost < current)
+            .min()
+            .map(|(_, neighbor)| neighbor)
+    }
+
+    /// Return the world position of the next tile center to walk to from a world position
+    pub fn next_waypoint(&self, grid: &Grid, position: Vec3) -> Option<Vec3> {
+        let coord = grid.world_to_coord(position);
+        self.next_tile(grid, (coord[0], coord[1]))
+            .map(|next| grid.coord_to_tile([next.0, next.1]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn following_the_field_reaches_the_goal() {
+        let mut grid = Grid::default();
+        for z in -10..=3 {
+            grid.set_obstructed((5, z), true);
+        }
+        let field = FlowField::build(&grid, (10, 0));
+
+        let mut tile = (0, 0);
+        for _ in 0..100 {
+            match field.next_tile(&grid, tile) {
+                Some(next) => tile = next,
+                None => break,
+            }
+        }
+
+        assert_eq!(tile, (10, 0));
+        assert_eq!(field.cost((10, 0)), Some(0));
+        assert_eq!(field.cost((5, 0)), None);
+    }
+}
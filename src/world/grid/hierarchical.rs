@@ -0,0 +1,646 @@
+//! Hierarchical pathfinding ( HPA* ) over the grid chunks.
+//!
+//! Every chunk is a cluster. The free tiles on both sides of the border between two chunks are the
+//! entrances, and the entrances of the same chunk are connected by the cost of the path between them.
+//! Long paths are searched in this small abstract graph and then refined to tiles one chunk at a time.
+
+use bevy::prelude::*;
+use hashbrown::{HashMap, HashSet};
+use pathfinding::directed::{astar::astar, dijkstra::dijkstra_all};
+use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc, time::Instant};
+
+use super::resources::{Endpoints, Grid, Path, PathRequest, PathSearch, PathfindingError};
+
+/// Pair of tiles in both sides of a border between chunks
+type Crossing = ((i32, i32), (i32, i32));
+/// The two chunks separated by a border, left or below first
+type Border = ((i32, i32), (i32, i32));
+/// Crossing of a border with the cost of stepping into its second and its first tile
+type Transition = (Crossing, u32, u32);
+/// Tile reached by a step and the cost of the step
+type Step = ((i32, i32), u32);
+/// Edges leaving each entrance
+type Edges = HashMap<(i32, i32), Vec<Step>>;
+/// Cost from the start and previous node of each node reached by the abstract search
+type Visited = HashMap<(i32, i32), (u32, (i32, i32))>;
+
+/// Entrances wider than this get one transition in each end instead of one in the middle
+const ENTRANCE_SPLIT: usize = 6;
+
+/// Entrances of a chunk and the edges between them.
+#[derive(Clone, Default)]
+struct Cluster {
+    entrances: Vec<(i32, i32)>,
+    /// Edges from an entrance to the other entrances of the chunk
+    edges: Edges,
+}
+
+/// Abstract graph of the chunk entrances of the [Grid].
+///
+/// The borders and chunks are shared between clones and replaced on repair, so the snapshots given to the
+/// pathfinding tasks only stop sharing the chunks repaired after they were taken.
+#[derive(Resource, Clone, Default)]
+pub struct HierarchicalGraph {
+    /// Transitions crossing each border, keyed by the two chunks it separates
+    borders: Arc<HashMap<Border, Arc<Vec<Transition>>>>,
+    /// Entrances and edges of each chunk
+    clusters: Arc<HashMap<(i32, i32), Arc<Cluster>>>,
+    built: bool,
+    /// [Grid::version] the graph was last built or repaired for
+    grid_version: u64,
+}
+
+impl HierarchicalGraph {
+    /// Build the graph for every chunk inside the grid bounds
+    pub fn build(grid: &Grid) -> Self {
+        let mut graph = Self {
+            built: true,
+            grid_version: grid.version(),
+            ..Default::default()
+        };
+
+        let clusters = clusters(grid);
+        for &cluster in &clusters {
+            for neighbor in [(cluster.0 + 1, cluster.1), (cluster.0, cluster.1 + 1)] {
+                if clusters.contains(&neighbor) {
+                    graph.compute_border(grid, cluster, neighbor);
+                }
+            }
+        }
+        for &cluster in &clusters {
+            graph.compute_cluster(grid, cluster);
+        }
+
+        graph
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.built
+    }
+
+    /// Return the [Grid::version] the graph was last built or repaired for
+    pub fn grid_version(&self) -> u64 {
+        self.grid_version
+    }
+
+    /// Update only the chunks holding the changed tiles and the borders around them
+    pub fn repair(&mut self, grid: &Grid, changed_tiles: impl IntoIterator<Item = (i32, i32)>) {
+        let clusters = clusters(grid);
+        let changed_chunks: HashSet<(i32, i32)> = changed_tiles
+            .into_iter()
+            .map(|tile| grid.coord_to_chunk(tile))
+            .filter(|chunk| clusters.contains(chunk))
+            .collect();
+
+        let mut affected = HashSet::new();
+        for &chunk in &changed_chunks {
+            affected.insert(chunk);
+            for neighbor in neighbor_clusters(chunk) {
+                if !clusters.contains(&neighbor) {
+                    continue;
+                }
+                affected.insert(neighbor);
+                self.compute_border(grid, chunk.min(neighbor), chunk.max(neighbor));
+            }
+        }
+        for cluster in affected {
+            self.compute_cluster(grid, cluster);
+        }
+        self.grid_version = grid.version();
+    }
+
+    /// Find the transitions crossing the border between two chunks, `a` must be left or below `b`
+    fn compute_border(&mut self, grid: &Grid, a: (i32, i32), b: (i32, i32)) {
+        let size = grid.chunk_size;
+        let crossings: Vec<Crossing> = if a.1 == b.1 {
+            // vertical border
+            let x = b.0 * size;
+            (0..size)
+                .map(|i| ((x - 1, a.1 * size + i), (x, a.1 * size + i)))
+                .collect()
+        } else {
+            // horizontal border
+            let z = b.1 * size;
+            (0..size)
+                .map(|i| ((a.0 * size + i, z - 1), (a.0 * size + i, z)))
+                .collect()
+        };
+
+        let mut transitions = Vec::new();
+        let mut run: Vec<Crossing> = Vec::new();
+        // the last None closes the run reaching the end of the border
+        for crossing in crossings.into_iter().map(Some).chain([None]) {
+            if let Some(crossing) = crossing {
+                if !grid.is_obstructed(crossing.0) && !grid.is_obstructed(crossing.1) {
+                    run.push(crossing);
+                    continue;
+                }
+            }
+            if run.len() >= ENTRANCE_SPLIT {
+                transitions.push(run[0]);
+                transitions.push(run[run.len() - 1]);
+            } else if !run.is_empty() {
+                transitions.push(run[run.len() / 2]);
+            }
+            run.clear();
+        }
+
+        let cost = |tile: (i32, i32)| {
+            grid.get_tile(tile)
+                .map_or(0, |tile| grid.cost_model.step_cost(&tile, false))
+        };
+        let transitions = transitions
+            .into_iter()
+            .map(|(from, to)| ((from, to), cost(to), cost(from)))
+            .collect();
+        Arc::make_mut(&mut self.borders).insert((a, b), Arc::new(transitions));
+    }
+
+    /// Recalculate the entrances of a chunk and the edges between them
+    fn compute_cluster(&mut self, grid: &Grid, cluster: (i32, i32)) {
+        let mut entrances: Vec<(i32, i32)> = Vec::new();
+        for neighbor in neighbor_clusters(cluster) {
+            let key = (cluster.min(neighbor), cluster.max(neighbor));
+            for &((from, to), ..) in self
+                .borders
+                .get(&key)
+                .into_iter()
+                .flat_map(|transitions| transitions.iter())
+            {
+                for tile in [from, to] {
+                    if grid.coord_to_chunk(tile) == cluster && !entrances.contains(&tile) {
+                        entrances.push(tile);
+                    }
+                }
+            }
+        }
+
+        let mut edges = Edges::new();
+        for &entrance in &entrances {
+            let costs = dijkstra_all(&entrance, cluster_successors(grid, cluster));
+            let steps = entrances
+                .iter()
+                .filter_map(|other| costs.get(other).map(|&(_, cost)| (*other, cost)))
+                .collect();
+            edges.insert(entrance, steps);
+        }
+        Arc::make_mut(&mut self.clusters).insert(cluster, Arc::new(Cluster { entrances, edges }));
+    }
+
+    /// Search a path in the abstract graph and refine it to tiles.
+    ///
+    /// The budget of the request limits the abstract nodes expanded, the time and the length of the refined
+    /// path. When it runs out and the request allows it, returns a partial path to the explored node closest to
+    /// the goal. Paths inside a single chunk, and requests the abstract graph can not solve, fall back to the
+    /// tile search of [Grid::search] with the budget left.
+    pub fn search(&self, grid: &Grid, request: &PathRequest) -> PathSearch {
+        let started = Instant::now();
+        let (start, goal) = match grid.request_tiles(request) {
+            Ok(tiles) => tiles,
+            Err(error) => {
+                return PathSearch {
+                    result: Err(error),
+                    expansions: 0,
+                    elapsed: started.elapsed(),
+                }
+            }
+        };
+
+        let start_chunk = grid.coord_to_chunk(start);
+        let goal_chunk = grid.coord_to_chunk(goal);
+        if !self.built || start_chunk == goal_chunk {
+            return grid.search(request);
+        }
+
+        let entrance_costs = |tile: (i32, i32), chunk: (i32, i32)| -> Vec<Step> {
+            let costs = dijkstra_all(&tile, cluster_successors(grid, chunk));
+            self.clusters
+                .get(&chunk)
+                .into_iter()
+                .flat_map(|cluster| &cluster.entrances)
+                .filter_map(|entrance| costs.get(entrance).map(|&(_, cost)| (*entrance, cost)))
+                .collect()
+        };
+        let start_edges = entrance_costs(start, start_chunk);
+        let goal_edges: HashMap<(i32, i32), u32> =
+            entrance_costs(goal, goal_chunk).into_iter().collect();
+
+        let mut expansions = 0;
+        let successors = |node: (i32, i32)| {
+            let mut edges = self.edges(grid, node);
+            if node == start {
+                edges.extend(start_edges.iter().copied());
+            }
+            if let Some(&cost) = goal_edges.get(&node) {
+                edges.push((goal, cost));
+            }
+            edges
+        };
+        let outcome = abstract_astar(
+            grid,
+            request,
+            started,
+            (start, goal),
+            successors,
+            &mut expansions,
+        );
+
+        let result = match outcome {
+            AbstractPath::Found(nodes) => self
+                .refine(grid, &nodes)
+                .map(|tiles| limit_length(grid, request, (start, goal), tiles, false)),
+            AbstractPath::Exhausted(nodes) if request.allow_partial && nodes.len() > 1 => self
+                .refine(grid, &nodes)
+                .map(|tiles| limit_length(grid, request, (start, goal), tiles, true)),
+            AbstractPath::Exhausted(_) => {
+                Some(Err(PathfindingError::BudgetExhausted { start, goal }))
+            }
+            AbstractPath::Unreachable => None,
+        };
+
+        match result {
+            Some(result) => PathSearch {
+                result,
+                expansions,
+                elapsed: started.elapsed(),
+            },
+            None => {
+                let mut request = *request;
+                request.budget.max_expansions =
+                    request.budget.max_expansions.saturating_sub(expansions);
+                request.budget.timeout = request
+                    .budget
+                    .timeout
+                    .map(|timeout| timeout.saturating_sub(started.elapsed()));
+                let mut search = grid.search(&request);
+                search.expansions += expansions;
+                search.elapsed = started.elapsed();
+                search
+            }
+        }
+    }
+
+    /// Return the abstract edges leaving an entrance, to its own chunk and across its borders
+    fn edges(&self, grid: &Grid, node: (i32, i32)) -> Vec<Step> {
+        let chunk = grid.coord_to_chunk(node);
+        let intra = self
+            .clusters
+            .get(&chunk)
+            .and_then(|cluster| cluster.edges.get(&node))
+            .into_iter()
+            .flatten()
+            .copied();
+        let inter = neighbor_clusters(chunk)
+            .into_iter()
+            .filter_map(|neighbor| {
+                self.borders
+                    .get(&(chunk.min(neighbor), chunk.max(neighbor)))
+            })
+            .flat_map(|transitions| transitions.iter())
+            .filter_map(|&((from, to), to_cost, from_cost)| {
+                if from == node {
+                    Some((to, to_cost))
+                } else if to == node {
+                    Some((from, from_cost))
+                } else {
+                    None
+                }
+            });
+        intra.chain(inter).collect()
+    }
+
+    /// Convert the abstract nodes to tiles, searching the tiles between nodes of the same chunk.
+    ///
+    /// Returns None when a step can not be walked in the current grid.
+    fn refine(&self, grid: &Grid, nodes: &[(i32, i32)]) -> Option<Vec<(i32, i32)>> {
+        let mut tiles = vec![nodes[0]];
+        for pair in nodes.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let chunk = grid.coord_to_chunk(from);
+            if chunk != grid.coord_to_chunk(to) {
+                // the graph may be older than the grid, so the crossing could be blocked by now
+                if !grid
+                    .walkable_neighbors(from)
+                    .iter()
+                    .any(|&(neighbor, _)| neighbor == to)
+                {
+                    return None;
+                }
+                tiles.push(to);
+                continue;
+            }
+
+            let (segment, _) = astar(
+                &from,
+                cluster_successors(grid, chunk),
+                |&tile| grid.cost_model.heuristic(tile, to),
+                |&tile| tile == to,
+            )?;
+            tiles.extend(segment.into_iter().skip(1));
+        }
+        Some(tiles)
+    }
+}
+
+/// Outcome of a search in the abstract graph.
+enum AbstractPath {
+    /// Nodes from the start to the goal
+    Found(Vec<(i32, i32)>),
+    /// The budget ran out, nodes from the start to the explored node closest to the goal
+    Exhausted(Vec<(i32, i32)>),
+    Unreachable,
+}
+
+/// Run A* in the abstract graph, stopping when the budget of the request runs out
+fn abstract_astar(
+    grid: &Grid,
+    request: &PathRequest,
+    started: Instant,
+    (start, goal): Endpoints,
+    mut successors: impl FnMut((i32, i32)) -> Vec<Step>,
+    expansions: &mut usize,
+) -> AbstractPath {
+    let budget = &request.budget;
+    let heuristic = |node: (i32, i32)| grid.cost_model.heuristic(node, goal);
+
+    let mut visited: Visited = HashMap::from([(start, (0, start))]);
+    let mut closed: HashSet<(i32, i32)> = HashSet::new();
+    let mut open = BinaryHeap::from([Reverse((heuristic(start), heuristic(start), start))]);
+    let mut closest = (heuristic(start), start);
+
+    let nodes_to = |visited: &Visited, end: (i32, i32)| {
+        let mut nodes = vec![end];
+        let mut current = end;
+        while current != start {
+            current = visited[&current].1;
+            nodes.push(current);
+        }
+        nodes.reverse();
+        nodes
+    };
+
+    while let Some(Reverse((_, _, current))) = open.pop() {
+        if current == goal {
+            return AbstractPath::Found(nodes_to(&visited, goal));
+        }
+        if !closed.insert(current) {
+            continue;
+        }
+
+        let timed_out = budget
+            .timeout
+            .is_some_and(|timeout| started.elapsed() >= timeout);
+        if *expansions >= budget.max_expansions || timed_out {
+            return AbstractPath::Exhausted(nodes_to(&visited, closest.1));
+        }
+        *expansions += 1;
+
+        let cost = visited[&current].0;
+        for (neighbor, step_cost) in successors(current) {
+            let neighbor_cost = cost + step_cost;
+            if visited
+                .get(&neighbor)
+                .is_some_and(|&(known, _)| known <= neighbor_cost)
+            {
+                continue;
+            }
+            visited.insert(neighbor, (neighbor_cost, current));
+            let neighbor_heuristic = heuristic(neighbor);
+            if neighbor_heuristic < closest.0 {
+                closest = (neighbor_heuristic, neighbor);
+            }
+            open.push(Reverse((
+                neighbor_cost + neighbor_heuristic,
+                neighbor_heuristic,
+                neighbor,
+            )));
+        }
+    }
+
+    AbstractPath::Unreachable
+}
+
+/// Turn refined tiles into a path, cutting it to the maximum length of the request
+fn limit_length(
+    grid: &Grid,
+    request: &PathRequest,
+    (start, goal): Endpoints,
+    mut tiles: Vec<(i32, i32)>,
+    partial: bool,
+) -> Result<Path, PathfindingError> {
+    match request.budget.max_path_length {
+        Some(max) if tiles.len() > max + 1 => {
+            if !request.allow_partial {
+                return Err(PathfindingError::BudgetExhausted { start, goal });
+            }
+            tiles.truncate(max + 1);
+            Ok(grid.tiles_to_path(tiles, true))
+        }
+        _ => Ok(grid.tiles_to_path(tiles, partial)),
+    }
+}
+
+/// Return the chunks inside the grid bounds
+fn clusters(grid: &Grid) -> HashSet<(i32, i32)> {
+    let min = grid.coord_to_chunk(grid.bounds.min);
+    let max = grid.coord_to_chunk((grid.bounds.max.0 - 1, grid.bounds.max.1 - 1));
+    (min.0..=max.0)
+        .flat_map(|x| (min.1..=max.1).map(move |z| (x, z)))
+        .collect()
+}
+
+fn neighbor_clusters(cluster: (i32, i32)) -> [(i32, i32); 4] {
+    [
+        (cluster.0 - 1, cluster.1),
+        (cluster.0 + 1, cluster.1),
+        (cluster.0, cluster.1 - 1),
+        (cluster.0, cluster.1 + 1),
+    ]
+}
+
+/// Neighbors of a tile that do not leave the chunk
+fn cluster_successors(
+    grid: &Grid,
+    cluster: (i32, i32),
+) -> impl FnMut(&(i32, i32)) -> Vec<Step> + '_ {
+    move |&tile| {
+        grid.walkable_neighbors(tile)
+            .into_iter()
+            .filter(|(neighbor, _)| grid.coord_to_chunk(*neighbor) == cluster)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::grid::resources::FloorType;
+
+    fn tile(grid: &Grid, coord: (i32, i32)) -> Vec3 {
+        grid.coord_to_tile([coord.0, coord.1])
+    }
+
+    /// Return if every step is a walkable neighbor of the previous one
+    fn is_walkable(grid: &Grid, steps: &[Vec3]) -> bool {
+        steps.windows(2).all(|pair| {
+            let from = grid.world_to_coord(pair[0]);
+            let to = grid.world_to_coord(pair[1]);
+            grid.walkable_neighbors((from[0], from[1]))
+                .iter()
+                .any(|(neighbor, _)| *neighbor == (to[0], to[1]))
+        })
+    }
+
+    /// Long wall on x = 0 with a single gap in the top of the lot
+    fn long_wall(grid: &mut Grid) {
+        for z in -50..45 {
+            grid.set_obstructed((0, z), true);
+        }
+    }
+
+    #[test]
+    fn finds_paths_the_tile_search_can_not() {
+        let mut grid = Grid::default();
+        long_wall(&mut grid);
+        let graph = HierarchicalGraph::build(&grid);
+
+        let mut request = PathRequest::new(tile(&grid, (-10, -40)), tile(&grid, (10, -40)));
+        request.allow_partial = false;
+        assert!(grid.search(&request).result.is_err());
+
+        let path = graph.search(&grid, &request).result.unwrap();
+        assert!(!path.partial);
+        assert_eq!(grid.world_to_coord(path.steps[0]), [-10, -40]);
+        assert_eq!(grid.world_to_coord(*path.steps.last().unwrap()), [10, -40]);
+        assert!(is_walkable(&grid, &path.steps));
+    }
+
+    #[test]
+    fn repair_follows_new_walls() {
+        let mut grid = Grid::default();
+        let mut graph = HierarchicalGraph::build(&grid);
+        grid.take_changed_tiles();
+
+        long_wall(&mut grid);
+        let changed = grid.take_changed_tiles().tiles;
+        graph.repair(&grid, changed);
+
+        let request = PathRequest::new(tile(&grid, (-10, -40)), tile(&grid, (10, -40)));
+        let path = graph.search(&grid, &request).result.unwrap();
+        assert!(is_walkable(&grid, &path.steps));
+        assert!(path
+            .steps
+            .iter()
+            .any(|step| grid.world_to_coord(*step)[1] >= 45));
+
+        // removing the wall again opens the short way
+        for z in -50..45 {
+            grid.set_obstructed((0, z), false);
+        }
+        let changed = grid.take_changed_tiles().tiles;
+        graph.repair(&grid, changed);
+
+        let path = graph.search(&grid, &request).result.unwrap();
+        assert!(is_walkable(&grid, &path.steps));
+        assert!(path.steps.len() < 40);
+    }
+
+    #[test]
+    fn repair_follows_floor_and_occupant_changes() {
+        let mut grid = Grid::default();
+        let mut graph = HierarchicalGraph::build(&grid);
+        grid.take_changed_tiles();
+
+        for x in 0..16 {
+            for z in 0..16 {
+                grid.set_floor((x, z), FloorType::Carpet);
+            }
+        }
+        grid.reserve((15, 8), Entity::from_raw(0));
+        let changed = grid.take_changed_tiles();
+        assert!(changed.obstructed.is_empty());
+        graph.repair(&grid, changed.tiles);
+
+        let built = HierarchicalGraph::build(&grid);
+        let sorted = |graph: &HierarchicalGraph, node| {
+            let mut edges = graph.edges(&grid, node);
+            edges.sort();
+            edges
+        };
+        for &entrance in built
+            .clusters
+            .values()
+            .flat_map(|cluster| &cluster.entrances)
+        {
+            assert_eq!(sorted(&graph, entrance), sorted(&built, entrance));
+        }
+    }
+
+    #[test]
+    fn search_keeps_to_the_budget() {
+        let mut grid = Grid::default();
+        long_wall(&mut grid);
+        let graph = HierarchicalGraph::build(&grid);
+
+        let mut request = PathRequest::new(tile(&grid, (-10, -40)), tile(&grid, (10, -40)));
+        request.budget.max_expansions = 2;
+        request.allow_partial = false;
+        let search = graph.search(&grid, &request);
+        assert!(matches!(
+            search.result,
+            Err(PathfindingError::BudgetExhausted { .. })
+        ));
+        assert_eq!(search.expansions, 2);
+
+        request.allow_partial = true;
+        let path = graph.search(&grid, &request).result.unwrap();
+        assert!(path.partial);
+        assert!(is_walkable(&grid, &path.steps));
+
+        let mut request = PathRequest::new(tile(&grid, (-10, -40)), tile(&grid, (10, -40)));
+        request.budget.max_path_length = Some(10);
+        let path = graph.search(&grid, &request).result.unwrap();
+        assert!(path.partial);
+        assert_eq!(path.steps.len(), 11);
+    }
+
+    #[test]
+    fn stale_graph_does_not_cross_new_walls() {
+        let mut grid = Grid::default();
+        let graph = HierarchicalGraph::build(&grid);
+        // wall on the border between two chunks the graph does not know about yet
+        for z in -10..=10 {
+            grid.set_obstructed((16, z), true);
+        }
+
+        let request = PathRequest::new(tile(&grid, (10, 0)), tile(&grid, (22, 0)));
+        let path = graph.search(&grid, &request).result.unwrap();
+        assert!(!path.partial);
+        assert!(is_walkable(&grid, &path.steps));
+    }
+
+    #[test]
+    fn clones_share_the_chunks_that_were_not_repaired() {
+        let mut grid = Grid::default();
+        let mut graph = HierarchicalGraph::build(&grid);
+        grid.take_changed_tiles();
+        let snapshot = graph.clone();
+
+        grid.set_obstructed((1, 1), true);
+        let changed = grid.take_changed_tiles().tiles;
+        graph.repair(&grid, changed);
+
+        let shared = |chunk| Arc::ptr_eq(&graph.clusters[&chunk], &snapshot.clusters[&chunk]);
+        // only the chunk of the wall and its four neighbors were replaced
+        let repaired: Vec<_> = graph
+            .clusters
+            .keys()
+            .copied()
+            .filter(|&chunk| !shared(chunk))
+            .collect();
+        assert_eq!(repaired.len(), 5);
+        for chunk in [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1)] {
+            assert!(repaired.contains(&chunk));
+        }
+    }
+}
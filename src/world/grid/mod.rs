@@ -1,6 +1,7 @@
 //! Handle the world grid.
 //!
-//! Divides the space in plane xz by the grid_size creating tiles in world
+//! Divides the space in plane xz by the grid_size creating tiles in world.
+//! The tiles are stored in chunks and limited to the playable bounds of the lot.
 use bevy::prelude::*;
 
 pub mod systems;
@@ -11,12 +12,24 @@ pub mod resources;
 use resources::*;
 pub mod states;
 use states::*;
+pub mod events;
+use events::*;
+pub mod hierarchical;
+use hierarchical::*;
+pub mod flow_field;
 
 pub struct GridPlugin;
 
 impl Plugin for GridPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<Grid>();
+        app.init_resource::<HierarchicalGraph>();
+        app.init_resource::<GridSnapshot>();
+        app.add_event::<GridChanged>();
         app.add_systems(Update, show_grid);
+        app.add_systems(
+            Update,
+            (emit_grid_changes, update_hierarchical_graph).chain(),
+        );
     }
 }
@@ -1,27 +1,237 @@
 use bevy::prelude::*;
-use hashbrown::HashMap;
-use pathfinding::directed::astar::astar;
+use hashbrown::{HashMap, HashSet};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::world::physics::components::BoxCollider;
+use crate::world::{grid::hierarchical::HierarchicalGraph, physics::components::BoxCollider};
 
+/// Holds the tiles of the world split in square chunks of `chunk_size` tiles.
+///
+/// Only chunks with something built on them are stored, so the memory scales with the built area
+/// and not with the size of the lot. Tiles outside the [GridBounds] are never walkable.
+///
+/// The chunks are shared between clones and copied on write, so cloning the grid only copies the
+/// chunks that are modified afterwards.
 #[derive(Resource, Clone)]
 pub struct Grid {
     pub tile_size: f32,
-    pub hashmap: HashMap<(i32, i32), bool>,
+    pub chunk_size: i32,
+    pub bounds: GridBounds,
+    pub cost_model: CostModel,
+    pub diagonal_movement: DiagonalMovement,
+    chunks: Arc<HashMap<(i32, i32), Arc<Chunk>>>,
+    /// Tiles changed since the last [Grid::take_changed_tiles]
+    changed_tiles: TileChanges,
+    /// Increased every time a tile changes
+    version: u64,
+    /// Tile reserved by each entity
+    reservations: HashMap<Entity, (i32, i32)>,
 }
 
 impl Default for Grid {
     fn default() -> Self {
-        Grid {
-            tile_size: 0.2,
-            hashmap: HashMap::new(),
+        Grid::new(0.2, 16, GridBounds::new((-50, -50), (50, 50)))
+    }
+}
+
+/// Playable area of the grid in tile coordinates, `min` is inclusive and `max` is exclusive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridBounds {
+    pub min: (i32, i32),
+    pub max: (i32, i32),
+}
+
+impl GridBounds {
+    pub fn new(min: (i32, i32), max: (i32, i32)) -> Self {
+        Self { min, max }
+    }
+
+    pub fn contains(&self, coord: (i32, i32)) -> bool {
+        coord.0 >= self.min.0
+            && coord.0 < self.max.0
+            && coord.1 >= self.min.1
+            && coord.1 < self.max.1
+    }
+
+    /// Return the closest coordinate inside the bounds
+    pub fn clamp(&self, coord: (i32, i32)) -> (i32, i32) {
+        (
+            coord.0.clamp(self.min.0, self.max.0 - 1),
+            coord.1.clamp(self.min.1, self.max.1 - 1),
+        )
+    }
+}
+
+/// Everything the game knows about a single tile.
+///
+/// Tiles not stored in the grid are equal to [Tile::default].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub obstructed: bool,
+    /// Walkable tile under a door, it bounds the rooms like a wall
+    pub door: bool,
+    pub floor: FloorType,
+    pub room: Option<u32>,
+    /// Traversal cost of a straight step into this tile
+    pub cost: u32,
+    /// NPC that reserved this tile, see [Grid::reserve]
+    pub occupant: Option<Entity>,
+}
+
+impl Default for Tile {
+    fn default() -> Self {
+        Self {
+            obstructed: false,
+            door: false,
+            floor: FloorType::None,
+            room: None,
+            cost: 1,
+            occupant: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum FloorType {
+    #[default]
+    None,
+    Concrete,
+    Carpet,
+    Wood,
+    Ceramic,
+}
+
+/// Rule for diagonal steps between tiles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiagonalMovement {
+    /// Diagonal steps are allowed even between two obstructed tiles
+    Always,
+    /// Diagonal steps are only allowed when both orthogonal tiles next to it are free
+    #[default]
+    NoCornerCutting,
+    Never,
+}
+
+/// Costs used by the pathfinding to weight each step.
+///
+/// A step costs `straight` or `diagonal` multiplied by the tile weight, which is the [Tile] cost plus the
+/// extra cost of its floor and of an NPC standing on it. The weight is never lower than 1, so the octile
+/// heuristic of [CostModel::heuristic] never overestimates and A* keeps returning the cheapest path.
+#[derive(Clone, Debug)]
+pub struct CostModel {
+    pub straight: u32,
+    pub diagonal: u32,
+    pub floor_costs: HashMap<FloorType, u32>,
+    pub occupied_cost: u32,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            straight: 10,
+            diagonal: 14,
+            // corridors are concrete or ceramic, so NPCs prefer them over the carpet of the offices
+            floor_costs: HashMap::from([(FloorType::Carpet, 1)]),
+            occupied_cost: 4,
+        }
+    }
+}
+
+impl CostModel {
+    /// Return the weight of walking into a tile
+    pub fn tile_weight(&self, tile: &Tile) -> u32 {
+        let floor_cost = self.floor_costs.get(&tile.floor).copied().unwrap_or(0);
+        let occupied_cost = if tile.occupant.is_some() {
+            self.occupied_cost
+        } else {
+            0
+        };
+
+        (tile.cost + floor_cost + occupied_cost).max(1)
+    }
+
+    /// Return the cost of a step into a tile
+    pub fn step_cost(&self, tile: &Tile, diagonal: bool) -> u32 {
+        let base = if diagonal {
+            self.diagonal
+        } else {
+            self.straight
+        };
+
+        base * self.tile_weight(tile)
+    }
+
+    /// Octile distance between two tiles using the lowest possible weight
+    pub fn heuristic(&self, from: (i32, i32), to: (i32, i32)) -> u32 {
+        let dx = from.0.abs_diff(to.0);
+        let dy = from.1.abs_diff(to.1);
+        let diagonal = self.diagonal.min(2 * self.straight);
+
+        self.straight * (dx + dy) - (2 * self.straight - diagonal) * dx.min(dy)
+    }
+}
+
+/// Tiles that changed in a way that matters to the searches.
+#[derive(Clone, Debug, Default)]
+pub struct TileChanges {
+    /// Tiles whose obstruction or step cost changed
+    pub tiles: HashSet<(i32, i32)>,
+    /// Tiles whose obstruction changed, all of them are also in `tiles`
+    pub obstructed: HashSet<(i32, i32)>,
+    /// Tiles that gained or lost a door
+    pub doors: HashSet<(i32, i32)>,
+}
+
+impl TileChanges {
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty() && self.doors.is_empty()
+    }
+}
+
+/// A square block of tiles stored in row major order.
+#[derive(Clone)]
+pub struct Chunk {
+    tiles: Vec<Tile>,
+    used_count: usize,
+}
+
+impl Chunk {
+    fn new(chunk_size: i32) -> Self {
+        Self {
+            tiles: vec![Tile::default(); (chunk_size * chunk_size) as usize],
+            used_count: 0,
         }
     }
+
+    /// A chunk with only default tiles can be dropped from the grid
+    fn is_empty(&self) -> bool {
+        self.used_count == 0
+    }
 }
+
 impl Grid {
+    pub fn new(tile_size: f32, chunk_size: i32, bounds: GridBounds) -> Self {
+        Self {
+            tile_size,
+            chunk_size,
+            bounds,
+            cost_model: CostModel::default(),
+            diagonal_movement: DiagonalMovement::default(),
+            chunks: Arc::new(HashMap::new()),
+            changed_tiles: TileChanges::default(),
+            version: 0,
+            reservations: HashMap::new(),
+        }
+    }
+
     //world: position in world
     //tile: center position of a tile
     //index: index of a tile
+    //chunk: index of a chunk
 
     pub fn world_to_coord(&self, world_pos: Vec3) -> [i32; 2] {
         let index_vec = (world_pos / self.tile_size).floor();
@@ -41,21 +251,274 @@ impl Grid {
         tile
     }
 
-    /// Mark the tiles in hashmap that a collider is obstructuring
+    /// Return the chunk that holds the tile
+    pub fn coord_to_chunk(&self, coord: (i32, i32)) -> (i32, i32) {
+        (
+            coord.0.div_euclid(self.chunk_size),
+            coord.1.div_euclid(self.chunk_size),
+        )
+    }
+
+    /// Return the position of the tile inside the tiles vec of its chunk
+    fn local_index(&self, coord: (i32, i32)) -> usize {
+        let x = coord.0.rem_euclid(self.chunk_size);
+        let z = coord.1.rem_euclid(self.chunk_size);
+        (z * self.chunk_size + x) as usize
+    }
+
+    pub fn in_bounds(&self, coord: (i32, i32)) -> bool {
+        self.bounds.contains(coord)
+    }
+
+    /// Return the index of every chunk currently stored
+    pub fn loaded_chunks(&self) -> impl Iterator<Item = &(i32, i32)> {
+        self.chunks.keys()
+    }
+
+    /// Return the coordinates of all tiles of a chunk
+    pub fn chunk_tiles(&self, chunk: (i32, i32)) -> impl Iterator<Item = (i32, i32)> {
+        let size = self.chunk_size;
+        (0..size)
+            .flat_map(move |z| (0..size).map(move |x| (chunk.0 * size + x, chunk.1 * size + z)))
+    }
+
+    /// Return the data of a tile, or None when it is out of bounds
+    pub fn get_tile(&self, coord: (i32, i32)) -> Option<Tile> {
+        if !self.in_bounds(coord) {
+            return None;
+        }
+
+        match self.chunks.get(&self.coord_to_chunk(coord)) {
+            Some(chunk) => Some(chunk.tiles[self.local_index(coord)]),
+            None => Some(Tile::default()),
+        }
+    }
+
+    /// Change the data of a tile, loading or unloading its chunk when needed.
+    ///
+    /// Does nothing for tiles out of bounds.
+    pub fn update_tile(&mut self, coord: (i32, i32), update: impl FnOnce(&mut Tile)) {
+        if !self.in_bounds(coord) {
+            return;
+        }
+
+        let chunk_index = self.coord_to_chunk(coord);
+        let local_index = self.local_index(coord);
+        let previous = self.get_tile(coord).unwrap_or_default();
+        let mut tile = previous;
+        update(&mut tile);
+        if tile == previous {
+            return;
+        }
+
+        // Only copies the chunk when a snapshot still shares it
+        let chunk_size = self.chunk_size;
+        let chunks = Arc::make_mut(&mut self.chunks);
+        let chunk = Arc::make_mut(
+            chunks
+                .entry(chunk_index)
+                .or_insert_with(|| Arc::new(Chunk::new(chunk_size))),
+        );
+        chunk.tiles[local_index] = tile;
+        self.version += 1;
+
+        let was_used = previous != Tile::default();
+        let is_used = tile != Tile::default();
+        if tile.obstructed != previous.obstructed {
+            self.changed_tiles.obstructed.insert(coord);
+            self.changed_tiles.tiles.insert(coord);
+        } else if self.cost_model.tile_weight(&tile) != self.cost_model.tile_weight(&previous) {
+            self.changed_tiles.tiles.insert(coord);
+        }
+        if tile.door != previous.door {
+            self.changed_tiles.doors.insert(coord);
+        }
+
+        match (was_used, is_used) {
+            (false, true) => chunk.used_count += 1,
+            (true, false) => chunk.used_count -= 1,
+            _ => {}
+        }
+
+        if chunk.is_empty() {
+            chunks.remove(&chunk_index);
+        }
+    }
+
+    /// Return the version of the tiles, it changes every time a tile changes
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Return a read only copy of the grid sharing the chunks with it
+    pub fn snapshot(&self) -> Arc<Grid> {
+        Arc::new(Grid {
+            changed_tiles: TileChanges::default(),
+            ..self.clone()
+        })
+    }
+
+    /// Return and clear the tiles changed since the last call
+    pub fn take_changed_tiles(&mut self) -> TileChanges {
+        std::mem::take(&mut self.changed_tiles)
+    }
+
+    /// Return if a tile can not be walked. Tiles outside the bounds are always obstructed
+    pub fn is_obstructed(&self, coord: (i32, i32)) -> bool {
+        self.get_tile(coord).is_none_or(|tile| tile.obstructed)
+    }
+
+    pub fn set_obstructed(&mut self, coord: (i32, i32), obstructed: bool) {
+        self.update_tile(coord, |tile| tile.obstructed = obstructed);
+    }
+
+    pub fn is_door(&self, coord: (i32, i32)) -> bool {
+        self.get_tile(coord).is_some_and(|tile| tile.door)
+    }
+
+    pub fn set_door(&mut self, coord: (i32, i32), door: bool) {
+        self.update_tile(coord, |tile| tile.door = door);
+    }
+
+    pub fn floor(&self, coord: (i32, i32)) -> Option<FloorType> {
+        self.get_tile(coord).map(|tile| tile.floor)
+    }
+
+    pub fn set_floor(&mut self, coord: (i32, i32), floor: FloorType) {
+        self.update_tile(coord, |tile| tile.floor = floor);
+    }
+
+    pub fn room(&self, coord: (i32, i32)) -> Option<u32> {
+        self.get_tile(coord).and_then(|tile| tile.room)
+    }
+
+    pub fn set_room(&mut self, coord: (i32, i32), room: Option<u32>) {
+        self.update_tile(coord, |tile| tile.room = room);
+    }
+
+    pub fn cost(&self, coord: (i32, i32)) -> Option<u32> {
+        self.get_tile(coord).map(|tile| tile.cost)
+    }
+
+    pub fn set_cost(&mut self, coord: (i32, i32), cost: u32) {
+        self.update_tile(coord, |tile| tile.cost = cost);
+    }
+
+    pub fn occupant(&self, coord: (i32, i32)) -> Option<Entity> {
+        self.get_tile(coord).and_then(|tile| tile.occupant)
+    }
+
+    /// Reserve a tile for an entity, releasing the tile it reserved before.
+    ///
+    /// Fails when the tile is obstructed or already reserved by another entity.
+    pub fn reserve(&mut self, coord: (i32, i32), entity: Entity) -> bool {
+        if self.reserved_tile(entity) == Some(coord) {
+            return true;
+        }
+        if self.is_obstructed(coord) || !self.is_reservable(coord, Some(entity)) {
+            return false;
+        }
+
+        self.release(entity);
+        self.update_tile(coord, |tile| tile.occupant = Some(entity));
+        self.reservations.insert(entity, coord);
+        true
+    }
+
+    /// Release the tile reserved by an entity
+    pub fn release(&mut self, entity: Entity) {
+        if let Some(coord) = self.reservations.remove(&entity) {
+            self.update_tile(coord, |tile| tile.occupant = None);
+        }
+    }
+
+    /// Return the tile reserved by an entity
+    pub fn reserved_tile(&self, entity: Entity) -> Option<(i32, i32)> {
+        self.reservations.get(&entity).copied()
+    }
+
+    /// Return if a tile is not reserved by anyone other than the entity
+    pub fn is_reservable(&self, coord: (i32, i32), entity: Option<Entity>) -> bool {
+        self.occupant(coord)
+            .is_none_or(|occupant| Some(occupant) == entity)
+    }
+
+    /// Return every tile inside the rectangle between `min` and `max` ( both inclusive ), clamped to the bounds
+    pub fn tiles_in_area(
+        &self,
+        min: (i32, i32),
+        max: (i32, i32),
+    ) -> impl Iterator<Item = ((i32, i32), Tile)> + '_ {
+        let min = self.bounds.clamp(min);
+        let max = self.bounds.clamp(max);
+        (min.1..=max.1).flat_map(move |z| {
+            (min.0..=max.0).filter_map(move |x| self.get_tile((x, z)).map(|tile| ((x, z), tile)))
+        })
+    }
+
+    /// Return every tile that differs from the default one
+    pub fn stored_tiles(&self) -> impl Iterator<Item = ((i32, i32), &Tile)> + '_ {
+        self.chunks.iter().flat_map(move |(&chunk_index, chunk)| {
+            self.chunk_tiles(chunk_index)
+                .zip(chunk.tiles.iter())
+                .filter(|(_, tile)| **tile != Tile::default())
+        })
+    }
+
+    /// Return the coordinates of the stored tiles matching the predicate
+    pub fn find_tiles(&self, predicate: impl Fn(&Tile) -> bool) -> Vec<(i32, i32)> {
+        self.stored_tiles()
+            .filter(|(_, tile)| predicate(tile))
+            .map(|(coord, _)| coord)
+            .collect()
+    }
+
+    /// Return the coordinates of all tiles assigned to a room
+    pub fn room_tiles(&self, room: u32) -> Vec<(i32, i32)> {
+        self.find_tiles(|tile| tile.room == Some(room))
+    }
+
+    /// Apply the same change to every tile inside the rectangle between `min` and `max` ( both inclusive )
+    pub fn update_area(&mut self, min: (i32, i32), max: (i32, i32), update: impl Fn(&mut Tile)) {
+        let min = self.bounds.clamp(min);
+        let max = self.bounds.clamp(max);
+        for z in min.1..=max.1 {
+            for x in min.0..=max.0 {
+                self.update_tile((x, z), &update);
+            }
+        }
+    }
+
+    /// Mark the tiles in grid that a collider is obstructuring
     pub fn mark_tiles_from_collider(&mut self, collider: &BoxCollider) {
         for tile in self.tiles_covered_by_collider(collider) {
-            self.hashmap.insert(tile, true);
+            self.set_obstructed(tile, true);
         }
     }
 
-    /// Unmark the tiles in hashmap that a collider was obstructuring
+    /// Unmark the tiles in grid that a collider was obstructuring
     pub fn unmark_tiles_from_collider(&mut self, collider: &BoxCollider) {
         for tile in self.tiles_covered_by_collider(collider) {
-            self.hashmap.remove(&tile);
+            self.set_obstructed(tile, false);
+        }
+    }
+
+    /// Mark the tiles in grid under a door, they stay walkable
+    pub fn mark_door_from_collider(&mut self, collider: &BoxCollider) {
+        for tile in self.tiles_covered_by_collider(collider) {
+            self.set_door(tile, true);
         }
     }
 
-    fn tiles_covered_by_collider(&self, collider: &BoxCollider) -> Vec<(i32, i32)> {
+    /// Unmark the tiles in grid that were under a door
+    pub fn unmark_door_from_collider(&mut self, collider: &BoxCollider) {
+        for tile in self.tiles_covered_by_collider(collider) {
+            self.set_door(tile, false);
+        }
+    }
+
+    /// Return the tiles a collider is over
+    pub fn tiles_covered_by_collider(&self, collider: &BoxCollider) -> Vec<(i32, i32)> {
         let corners = collider.get_corners();
 
         let min_index = self.world_to_coord(
@@ -88,106 +551,1183 @@ impl Grid {
         covered_tiles
     }
 
-    pub fn get_tile_status(&self, x: i32, z: i32) -> Option<bool> {
-        self.hashmap.get(&(x, z)).cloned()
-    }
-
     pub fn obstructed(&self, translation: &Vec3) -> bool {
         let index = self.world_to_coord(*translation);
+        self.is_obstructed(array_to_tuple(index))
+    }
+    /// Return every tile touched by the segment between two world positions, in order.
+    ///
+    /// When the segment passes exactly by the corner of a tile both tiles beside the corner are returned.
+    pub fn tiles_on_segment(&self, from: Vec3, to: Vec3) -> Vec<(i32, i32)> {
+        let start = Vec2::new(from.x, from.z) / self.tile_size;
+        let end = Vec2::new(to.x, to.z) / self.tile_size;
+        let delta = end - start;
+
+        let mut tile = (start.x.floor() as i32, start.y.floor() as i32);
+        let end_tile = (end.x.floor() as i32, end.y.floor() as i32);
+        let step = (delta.x.signum() as i32, delta.y.signum() as i32);
+
+        // distance in t ( 0 to 1 ) to the next vertical and horizontal tile border
+        let next_border = |position: f32, tile: i32, delta: f32| {
+            if delta == 0.0 {
+                return f32::INFINITY;
+            }
+            let border = if delta > 0.0 { tile + 1 } else { tile } as f32;
+            (border - position) / delta
+        };
+        let mut t_max = Vec2::new(
+            next_border(start.x, tile.0, delta.x),
+            next_border(start.y, tile.1, delta.y),
+        );
+        let t_delta = Vec2::new(1.0 / delta.x.abs(), 1.0 / delta.y.abs());
 
-        match self.hashmap.get(&array_to_tuple(index)) {
-            Some(true) => return true,
-            _ => return false,
+        let mut tiles = vec![tile];
+        let max_steps = (end_tile.0 - tile.0).abs() + (end_tile.1 - tile.1).abs();
+        for _ in 0..max_steps {
+            if tile == end_tile {
+                break;
+            }
+            if (t_max.x - t_max.y).abs() < f32::EPSILON {
+                // crossing by the corner
+                tiles.push((tile.0 + step.0, tile.1));
+                tiles.push((tile.0, tile.1 + step.1));
+                tile = (tile.0 + step.0, tile.1 + step.1);
+                t_max += t_delta;
+            } else if t_max.x < t_max.y {
+                tile.0 += step.0;
+                t_max.x += t_delta.x;
+            } else {
+                tile.1 += step.1;
+                t_max.y += t_delta.y;
+            }
+            tiles.push(tile);
         }
+
+        tiles
+    }
+
+    /// Return if a straight walk between two world positions does not touch any obstructed tile
+    pub fn line_of_sight(&self, from: Vec3, to: Vec3) -> bool {
+        self.tiles_on_segment(from, to)
+            .into_iter()
+            .all(|tile| !self.is_obstructed(tile))
     }
-    pub fn find_path(&self, start: &Vec3, end: &Vec3) -> Result<Path, PathfindingError> {
-        const MAX_ITERATIONS: i32 = 1000;
-        const STRAIGHT_COST: i32 = 1;
-        const DIAGONAL_COST: i32 = 2;
 
-        if self.obstructed(start) || self.obstructed(end) {
-            return Err(PathfindingError {});
+    /// Return the cost of walking straight between two world positions, or None when it touches an obstructed tile.
+    ///
+    /// A step to a neighbor tile costs the same as in the searches. Longer segments cost the octile distance
+    /// weighted by the heaviest tile they touch, so they never look cheaper than the tiles they cross.
+    pub fn segment_cost(&self, from: Vec3, to: Vec3) -> Option<u32> {
+        let start = array_to_tuple(self.world_to_coord(from));
+        let end = array_to_tuple(self.world_to_coord(to));
+        let mut weight = 1;
+        for coord in self.tiles_on_segment(from, to) {
+            let tile = self.get_tile(coord).filter(|tile| !tile.obstructed)?;
+            if coord != start {
+                weight = weight.max(self.cost_model.tile_weight(&tile));
+            }
         }
 
-        let start_index = array_to_tuple(self.world_to_coord(*start));
-        let end_index = array_to_tuple(self.world_to_coord(*end));
+        if start.0.abs_diff(end.0) <= 1 && start.1.abs_diff(end.1) <= 1 {
+            let diagonal = start.0 != end.0 && start.1 != end.1;
+            return Some(self.cost_model.step_cost(&self.get_tile(end)?, diagonal));
+        }
+        Some(self.cost_model.heuristic(start, end) * weight)
+    }
 
-        let mut iterations = 0;
+    /// Return the tiles that can be reached in one step from a tile and the cost of that step
+    pub fn walkable_neighbors(&self, (x, y): (i32, i32)) -> Vec<((i32, i32), u32)> {
+        let straight = [
+            (x - 1, y), // left
+            (x + 1, y), // right
+            (x, y - 1), // down
+            (x, y + 1), // up
+        ];
+        let diagonal = [
+            (x - 1, y - 1), // down left
+            (x + 1, y - 1), // down right
+            (x - 1, y + 1), // up left
+            (x + 1, y + 1), // up right
+        ];
 
-        let neighbors = |&(x, y): &(i32, i32)| {
-            iterations += 1;
-            if iterations > MAX_ITERATIONS {
-                return vec![];
+        let step = |index: (i32, i32), diagonal: bool| {
+            // out of bounds tiles return None, this clamps the search to the bounds
+            let tile = self.get_tile(index)?;
+            if tile.obstructed {
+                return None;
             }
+            Some((index, self.cost_model.step_cost(&tile, diagonal)))
+        };
 
-            println!("{}", iterations);
-            vec![
-                ((x - 1, y), STRAIGHT_COST),     // left
-                ((x + 1, y), STRAIGHT_COST),     // right
-                ((x, y - 1), STRAIGHT_COST),     // down
-                ((x, y + 1), STRAIGHT_COST),     // up
-                ((x - 1, y - 1), DIAGONAL_COST), // down left
-                ((x + 1, y - 1), DIAGONAL_COST), // down right
-                ((x - 1, y + 1), DIAGONAL_COST), // up left
-                ((x + 1, y + 1), DIAGONAL_COST), // up right
-            ]
+        let mut neighbors: Vec<_> = straight
             .into_iter()
-            .filter_map(|(index, cost)| {
-                match self.hashmap.get(&index) {
-                    Some(true) => None,       // Tile obstruído
-                    _ => Some((index, cost)), // Tile não obstruído ou não presente no hashmap
+            .filter_map(|index| step(index, false))
+            .collect();
+
+        for index in diagonal {
+            let allowed = match self.diagonal_movement {
+                DiagonalMovement::Always => true,
+                DiagonalMovement::NoCornerCutting => {
+                    !self.is_obstructed((index.0, y)) && !self.is_obstructed((x, index.1))
                 }
-            })
-            .collect::<Vec<_>>()
+                DiagonalMovement::Never => false,
+            };
+            if allowed {
+                neighbors.extend(step(index, true));
+            }
+        }
+
+        neighbors
+    }
+
+    /// Return the free tile closest to a world position, searching in rings around it up to `max_radius` tiles
+    pub fn nearest_free_tile(&self, world_pos: Vec3, max_radius: i32) -> Option<(i32, i32)> {
+        self.nearest_tile(world_pos, max_radius, |coord| !self.is_obstructed(coord))
+    }
+
+    /// Return the nearest tile that is not obstructed nor reserved by anyone other than the entity
+    pub fn nearest_reservable_tile(
+        &self,
+        world_pos: Vec3,
+        max_radius: i32,
+        entity: Option<Entity>,
+    ) -> Option<(i32, i32)> {
+        self.nearest_tile(world_pos, max_radius, |coord| {
+            !self.is_obstructed(coord) && self.is_reservable(coord, entity)
+        })
+    }
+
+    /// Return the nearest tile accepted by `is_free`, searching rings up to `max_radius` tiles away
+    fn nearest_tile(
+        &self,
+        world_pos: Vec3,
+        max_radius: i32,
+        is_free: impl Fn((i32, i32)) -> bool,
+    ) -> Option<(i32, i32)> {
+        let center = array_to_tuple(self.world_to_coord(world_pos));
+        if is_free(center) {
+            return Some(center);
+        }
+
+        let distance = |coord: (i32, i32)| {
+            let tile = self.coord_to_tile([coord.0, coord.1]);
+            Vec2::new(tile.x - world_pos.x, tile.z - world_pos.z).length()
         };
 
-        let heuristic = |&index: &(i32, i32)| {
-            let dx = (index.0 - end_index.0).abs();
-            let dy = (index.1 - end_index.1).abs();
+        let mut best: Option<((i32, i32), f32)> = None;
+        for radius in 1..=max_radius {
+            // the tiles of this ring can not be closer than the best one already found
+            if let Some((_, best_distance)) = best {
+                if (radius as f32 - 0.5) * self.tile_size > best_distance {
+                    break;
+                }
+            }
+
+            for coord in ring(center, radius) {
+                if !is_free(coord) {
+                    continue;
+                }
+                let coord_distance = distance(coord);
+                if best.is_none_or(|(_, best_distance)| coord_distance < best_distance) {
+                    best = Some((coord, coord_distance));
+                }
+            }
+        }
+
+        best.map(|(coord, _)| coord)
+    }
+
+    /// Find a complete path between two world positions with the default budget
+    pub fn find_path(&self, start: &Vec3, end: &Vec3) -> Result<Path, PathfindingError> {
+        let request = PathRequest {
+            retarget: false,
+            allow_partial: false,
+            ..PathRequest::new(*start, *end)
+        };
+        self.search(&request).result
+    }
 
-            STRAIGHT_COST * (dx + dy) + (DIAGONAL_COST - 2 * STRAIGHT_COST) * dx.min(dy)
+    /// Find a path like [Grid::find_path], but when the start or the end are obstructed walk from or to the
+    /// nearest free tile instead
+    pub fn find_path_to_nearest(&self, start: &Vec3, end: &Vec3) -> Result<Path, PathfindingError> {
+        let request = PathRequest {
+            allow_partial: false,
+            ..PathRequest::new(*start, *end)
         };
+        self.search(&request).result
+    }
 
-        let solution = astar(&start_index, neighbors, heuristic, |&index| {
-            index == end_index
-        });
+    /// Run an A* search limited by the budget of the request.
+    ///
+    /// When the budget runs out and the request allows it, returns a partial path to the explored tile closest
+    /// to the goal.
+    pub fn search(&self, request: &PathRequest) -> PathSearch {
+        let started = Instant::now();
+        let mut expansions = 0;
+        let result = self.run_search(request, started, &mut expansions);
 
-        if let Some((path, _)) = solution {
-            Ok(Path {
-                steps: path
-                    .into_iter()
-                    .map(|index| self.coord_to_tile(index.into()))
-                    .collect(),
-            })
-        } else {
-            Err(PathfindingError {})
+        PathSearch {
+            result,
+            expansions,
+            elapsed: started.elapsed(),
+        }
+    }
+
+    /// Return the start and goal tiles of a request, retargeted to free tiles when the request allows it
+    pub fn request_tiles(&self, request: &PathRequest) -> Result<Endpoints, PathfindingError> {
+        let start_index = match request.retarget {
+            true => self.nearest_free_tile(request.start, NEAREST_SEARCH_RADIUS),
+            false => None,
+        }
+        .unwrap_or_else(|| array_to_tuple(self.world_to_coord(request.start)));
+        // NPCs queue next to a goal reserved by someone else instead of walking over it
+        let end_index = match request.retarget {
+            true => {
+                self.nearest_reservable_tile(request.goal, NEAREST_SEARCH_RADIUS, request.entity)
+            }
+            false => None,
+        }
+        .unwrap_or_else(|| array_to_tuple(self.world_to_coord(request.goal)));
+
+        for index in [start_index, end_index] {
+            if !self.in_bounds(index) {
+                return Err(PathfindingError::OutOfBounds(index));
+            }
+        }
+        if self.is_obstructed(start_index) {
+            return Err(PathfindingError::StartObstructed(start_index));
+        }
+        if self.is_obstructed(end_index) {
+            return Err(PathfindingError::GoalObstructed(end_index));
+        }
+
+        Ok((start_index, end_index))
+    }
+
+    fn run_search(
+        &self,
+        request: &PathRequest,
+        started: Instant,
+        expansions: &mut usize,
+    ) -> Result<Path, PathfindingError> {
+        let (start_index, end_index) = self.request_tiles(request)?;
+        let budget = &request.budget;
+        let heuristic = |index: (i32, i32)| self.cost_model.heuristic(index, end_index);
+
+        let mut visited: HashMap<(i32, i32), VisitedTile> = HashMap::new();
+        let mut closed: HashSet<(i32, i32)> = HashSet::new();
+        let mut open = BinaryHeap::new();
+        let mut closest = (heuristic(start_index), start_index);
+        let mut exhausted = false;
+
+        visited.insert(
+            start_index,
+            VisitedTile {
+                cost: 0,
+                length: 0,
+                previous: start_index,
+            },
+        );
+        open.push(Reverse((
+            heuristic(start_index),
+            heuristic(start_index),
+            start_index,
+        )));
+
+        while let Some(Reverse((_, _, current))) = open.pop() {
+            if current == end_index {
+                return Ok(self.build_path(&visited, start_index, end_index, false));
+            }
+            if !closed.insert(current) {
+                continue;
+            }
+
+            let timed_out = budget
+                .timeout
+                .is_some_and(|timeout| started.elapsed() >= timeout);
+            if *expansions >= budget.max_expansions || timed_out {
+                exhausted = true;
+                break;
+            }
+            *expansions += 1;
+
+            let VisitedTile { cost, length, .. } = visited[&current];
+            if budget.max_path_length.is_some_and(|max| length >= max) {
+                exhausted = true;
+                continue;
+            }
+
+            for (neighbor, step_cost) in self.walkable_neighbors(current) {
+                let neighbor_cost = cost + step_cost;
+                if visited
+                    .get(&neighbor)
+                    .is_some_and(|known| known.cost <= neighbor_cost)
+                {
+                    continue;
+                }
+
+                visited.insert(
+                    neighbor,
+                    VisitedTile {
+                        cost: neighbor_cost,
+                        length: length + 1,
+                        previous: current,
+                    },
+                );
+                let neighbor_heuristic = heuristic(neighbor);
+                if neighbor_heuristic < closest.0 {
+                    closest = (neighbor_heuristic, neighbor);
+                }
+                open.push(Reverse((
+                    neighbor_cost + neighbor_heuristic,
+                    neighbor_heuristic,
+                    neighbor,
+                )));
+            }
+        }
+
+        if !exhausted {
+            return Err(PathfindingError::GoalUnreachable {
+                start: start_index,
+                goal: end_index,
+            });
+        }
+        if request.allow_partial && closest.1 != start_index {
+            return Ok(self.build_path(&visited, start_index, closest.1, true));
+        }
+        Err(PathfindingError::BudgetExhausted {
+            start: start_index,
+            goal: end_index,
+        })
+    }
+
+    /// Walk back the visited tiles from the end to the start
+    fn build_path(
+        &self,
+        visited: &HashMap<(i32, i32), VisitedTile>,
+        start: (i32, i32),
+        end: (i32, i32),
+        partial: bool,
+    ) -> Path {
+        let mut indexes = vec![end];
+        let mut current = end;
+        while current != start {
+            current = visited[&current].previous;
+            indexes.push(current);
+        }
+        indexes.reverse();
+
+        self.tiles_to_path(indexes, partial)
+    }
+
+    /// Convert tile coordinates to a path walking by the center of each tile
+    pub fn tiles_to_path(&self, tiles: Vec<(i32, i32)>, partial: bool) -> Path {
+        Path {
+            steps: tiles
+                .into_iter()
+                .map(|index| self.coord_to_tile(index.into()))
+                .collect(),
+            partial,
+        }
+    }
+}
+
+/// Start and goal tiles of a search
+pub type Endpoints = ((i32, i32), (i32, i32));
+
+/// A tile reached by the search.
+struct VisitedTile {
+    /// Cost from the start
+    cost: u32,
+    /// Steps from the start
+    length: usize,
+    previous: (i32, i32),
+}
+
+/// Limits of a single path search.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchBudget {
+    /// Maximum number of tiles expanded by the search
+    pub max_expansions: usize,
+    /// Maximum number of steps of the returned path
+    pub max_path_length: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for SearchBudget {
+    fn default() -> Self {
+        Self {
+            max_expansions: 1000,
+            max_path_length: None,
+            timeout: None,
+        }
+    }
+}
+
+/// A path search between two world positions.
+#[derive(Clone, Copy, Debug)]
+pub struct PathRequest {
+    pub start: Vec3,
+    pub goal: Vec3,
+    pub budget: SearchBudget,
+    /// Entity that will walk the path, the tiles it reserved are not avoided
+    pub entity: Option<Entity>,
+    /// Search from and to the nearest free tiles when the start or the goal are obstructed,
+    /// or when the goal is reserved by another entity
+    pub retarget: bool,
+    /// Return a path to the closest explored tile when the budget runs out
+    pub allow_partial: bool,
+}
+
+impl PathRequest {
+    pub fn new(start: Vec3, goal: Vec3) -> Self {
+        Self {
+            start,
+            goal,
+            budget: SearchBudget::default(),
+            entity: None,
+            retarget: true,
+            allow_partial: true,
         }
     }
 }
 
+/// Result of a path search and how much work it took.
+#[derive(Debug)]
+pub struct PathSearch {
+    pub result: Result<Path, PathfindingError>,
+    pub expansions: usize,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug)]
 pub struct Path {
     pub steps: Vec<Vec3>,
+    /// The path stops before the goal because the search budget ran out
+    pub partial: bool,
 }
 
 impl Path {
-    pub fn optimize_corners(&mut self) {
-        // i must be tracked here because vec len changes
-        let mut i = 0;
-        while i + 2 < self.steps.len() {
-            let first_step = &self.steps[i];
-            let third_step = &self.steps[i + 2];
-            //If both x and y change then this is a corner
-            if first_step.x != third_step.x && first_step.y != third_step.y {
-                self.steps.remove(i + 1);
+    /// Remove every step that can be skipped walking in a straight line ( string pulling ).
+    ///
+    /// From each kept step jumps to the furthest step, up to [SMOOTH_LOOKAHEAD] steps ahead, that can be walked
+    /// straight without costing more than the steps it skips, so the path turns only where something is in the
+    /// way and keeps going around expensive tiles.
+    pub fn smooth(&mut self, grid: &Grid) {
+        if self.steps.len() < 3 {
+            return;
+        }
+
+        // cost from the first step to each step
+        let mut walked: Vec<u32> = vec![0];
+        for pair in self.steps.windows(2) {
+            let cost = grid.segment_cost(pair[0], pair[1]).unwrap_or(u32::MAX);
+            walked.push(walked[walked.len() - 1].saturating_add(cost));
+        }
+
+        let mut smoothed = vec![self.steps[0]];
+        let mut anchor = 0;
+        while anchor < self.steps.len() - 1 {
+            let mut next = anchor + 1;
+            let furthest = (anchor + SMOOTH_LOOKAHEAD).min(self.steps.len() - 1);
+            for candidate in (anchor + 2..=furthest).rev() {
+                let skipped = walked[candidate] - walked[anchor];
+                if grid
+                    .segment_cost(self.steps[anchor], self.steps[candidate])
+                    .is_some_and(|cost| cost <= skipped)
+                {
+                    next = candidate;
+                    break;
+                }
             }
-            i += 1;
+            smoothed.push(self.steps[next]);
+            anchor = next;
         }
+
+        self.steps = smoothed;
     }
 }
 
-#[derive(Debug)]
-pub struct PathfindingError;
+/// Furthest step [Path::smooth] tries to reach from each kept step
+pub const SMOOTH_LOOKAHEAD: usize = 32;
+
+/// Reason why a path could not be found, holding the tile coordinates involved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathfindingError {
+    StartObstructed((i32, i32)),
+    GoalObstructed((i32, i32)),
+    GoalUnreachable { start: (i32, i32), goal: (i32, i32) },
+    BudgetExhausted { start: (i32, i32), goal: (i32, i32) },
+    OutOfBounds((i32, i32)),
+}
+
+impl PathfindingError {
+    /// Return the tile that caused the failure
+    pub fn coord(&self) -> (i32, i32) {
+        match *self {
+            PathfindingError::StartObstructed(coord) => coord,
+            PathfindingError::GoalObstructed(coord) => coord,
+            PathfindingError::GoalUnreachable { goal, .. } => goal,
+            PathfindingError::BudgetExhausted { goal, .. } => goal,
+            PathfindingError::OutOfBounds(coord) => coord,
+        }
+    }
+
+    /// Return the goal tile of the failed request, when the error knows it
+    pub fn goal(&self) -> Option<(i32, i32)> {
+        match *self {
+            PathfindingError::GoalObstructed(goal)
+            | PathfindingError::GoalUnreachable { goal, .. }
+            | PathfindingError::BudgetExhausted { goal, .. } => Some(goal),
+            PathfindingError::StartObstructed(_) | PathfindingError::OutOfBounds(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PathfindingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathfindingError::StartObstructed(coord) => {
+                write!(f, "start tile {:?} is obstructed", coord)
+            }
+            PathfindingError::GoalObstructed(coord) => {
+                write!(f, "goal tile {:?} is obstructed", coord)
+            }
+            PathfindingError::GoalUnreachable { start, goal } => {
+                write!(f, "goal tile {:?} is unreachable from {:?}", goal, start)
+            }
+            PathfindingError::BudgetExhausted { start, goal } => {
+                write!(
+                    f,
+                    "search budget exhausted going from {:?} to {:?}",
+                    start, goal
+                )
+            }
+            PathfindingError::OutOfBounds(coord) => write!(f, "tile {:?} is out of bounds", coord),
+        }
+    }
+}
+
+impl std::error::Error for PathfindingError {}
+
+/// How far in tiles [Grid::find_path_to_nearest] looks for a free tile
+pub const NEAREST_SEARCH_RADIUS: i32 = 10;
+
+/// Return the tiles at exactly `radius` tiles ( chebyshev distance ) from the center
+fn ring(center: (i32, i32), radius: i32) -> Vec<(i32, i32)> {
+    let mut tiles = Vec::new();
+    for dx in -radius..=radius {
+        tiles.push((center.0 + dx, center.1 - radius));
+        tiles.push((center.0 + dx, center.1 + radius));
+    }
+    for dz in (-radius + 1)..radius {
+        tiles.push((center.0 - radius, center.1 + dz));
+        tiles.push((center.0 + radius, center.1 + dz));
+    }
+    tiles
+}
 
 fn array_to_tuple(arr: [i32; 2]) -> (i32, i32) {
     (arr[0], arr[1])
 }
+
+/// Snapshots of the grid and its hierarchical graph shared by all the pathfinding tasks.
+///
+/// A new snapshot is only taken when the grid or the graph changed since the last one, and it shares the chunks
+/// that did not change with them.
+#[derive(Resource, Default)]
+pub struct GridSnapshot {
+    grid: Option<Arc<Grid>>,
+    graph: Option<Arc<HierarchicalGraph>>,
+}
+
+impl GridSnapshot {
+    /// Return the snapshots of the current grid and graph, taking new ones when they are outdated
+    pub fn get(
+        &mut self,
+        grid: &Grid,
+        graph: &HierarchicalGraph,
+    ) -> (Arc<Grid>, Arc<HierarchicalGraph>) {
+        let grid_snapshot = match &self.grid {
+            Some(snapshot) if snapshot.version() == grid.version() => snapshot.clone(),
+            _ => self.grid.insert(grid.snapshot()).clone(),
+        };
+        let graph_snapshot = match &self.graph {
+            Some(snapshot)
+                if snapshot.is_built() == graph.is_built()
+                    && snapshot.grid_version() == graph.grid_version() =>
+            {
+                snapshot.clone()
+            }
+            _ => self.graph.insert(Arc::new(graph.clone())).clone(),
+        };
+
+        (grid_snapshot, graph_snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mark a single tile with a collider smaller than the tile
+    fn wall_at(grid: &mut Grid, coord: (i32, i32)) {
+        let center = grid.coord_to_tile([coord.0, coord.1]);
+        let collider = BoxCollider::new(center, Quat::IDENTITY, Vec3::splat(grid.tile_size / 2.0));
+        grid.mark_tiles_from_collider(&collider);
+    }
+
+    fn tile(grid: &Grid, coord: (i32, i32)) -> Vec3 {
+        grid.coord_to_tile([coord.0, coord.1])
+    }
+
+    /// Return if any diagonal step of the path passes by an obstructed orthogonal tile
+    fn cuts_corners(grid: &Grid, path: &Path) -> bool {
+        path.steps.windows(2).any(|pair| {
+            let from = array_to_tuple(grid.world_to_coord(pair[0]));
+            let to = array_to_tuple(grid.world_to_coord(pair[1]));
+            from.0 != to.0
+                && from.1 != to.1
+                && (grid.is_obstructed((to.0, from.1)) || grid.is_obstructed((from.0, to.1)))
+        })
+    }
+
+    #[test]
+    fn collider_marks_only_its_tile() {
+        let mut grid = Grid::default();
+        wall_at(&mut grid, (1, 0));
+
+        assert!(grid.is_obstructed((1, 0)));
+        assert!(!grid.is_obstructed((0, 0)));
+        assert!(!grid.is_obstructed((2, 0)));
+        assert!(!grid.is_obstructed((1, 1)));
+    }
+
+    #[test]
+    fn diagonal_between_two_walls_is_blocked() {
+        let mut grid = Grid::default();
+        wall_at(&mut grid, (1, 0));
+        wall_at(&mut grid, (0, 1));
+
+        let path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (1, 1)))
+            .unwrap();
+
+        assert!(path.steps.len() > 2);
+        assert!(!cuts_corners(&grid, &path));
+    }
+
+    #[test]
+    fn diagonal_next_to_one_wall_is_blocked() {
+        let mut grid = Grid::default();
+        wall_at(&mut grid, (1, 0));
+
+        let path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (1, 1)))
+            .unwrap();
+
+        assert_eq!(path.steps.len(), 3);
+        assert!(!cuts_corners(&grid, &path));
+    }
+
+    #[test]
+    fn wall_joint_can_not_be_crossed() {
+        let mut grid = Grid::default();
+        // diagonal wall where each tile only touches the next one by the corner
+        for i in 0..6 {
+            wall_at(&mut grid, (i, -i));
+        }
+
+        let path = grid
+            .find_path(&tile(&grid, (2, -1)), &tile(&grid, (1, -2)))
+            .unwrap();
+
+        // the NPC has to walk around the end of the wall
+        assert!(path.steps.len() > 4);
+        assert!(!cuts_corners(&grid, &path));
+    }
+
+    #[test]
+    fn errors_carry_the_offending_tile() {
+        let mut grid = Grid::default();
+        wall_at(&mut grid, (3, 3));
+
+        let free = tile(&grid, (0, 0));
+        assert_eq!(
+            grid.find_path(&tile(&grid, (3, 3)), &free).unwrap_err(),
+            PathfindingError::StartObstructed((3, 3))
+        );
+        assert_eq!(
+            grid.find_path(&free, &tile(&grid, (3, 3))).unwrap_err(),
+            PathfindingError::GoalObstructed((3, 3))
+        );
+        assert_eq!(
+            grid.find_path(&free, &tile(&grid, (80, 0))).unwrap_err(),
+            PathfindingError::OutOfBounds((80, 0))
+        );
+    }
+
+    #[test]
+    fn enclosed_goal_is_unreachable() {
+        let mut grid = Grid::default();
+        for (x, z) in [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ] {
+            wall_at(&mut grid, (x, z));
+        }
+
+        let error = grid
+            .find_path(&tile(&grid, (5, 5)), &tile(&grid, (0, 0)))
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            PathfindingError::GoalUnreachable { .. } | PathfindingError::BudgetExhausted { .. }
+        ));
+        assert_eq!(error.coord(), (0, 0));
+    }
+
+    #[test]
+    fn nearest_free_tile_next_to_desk() {
+        let mut grid = Grid::default();
+        // a desk covering 3x2 tiles
+        for x in 0..3 {
+            for z in 0..2 {
+                wall_at(&mut grid, (x, z));
+            }
+        }
+
+        assert_eq!(grid.nearest_free_tile(tile(&grid, (1, 5)), 5), Some((1, 5)));
+        assert_eq!(grid.nearest_free_tile(tile(&grid, (1, 1)), 5), Some((1, 2)));
+        assert!(matches!(
+            grid.nearest_free_tile(tile(&grid, (0, 0)), 5),
+            Some((-1, 0)) | Some((0, -1))
+        ));
+        assert_eq!(grid.nearest_free_tile(tile(&grid, (1, 1)), 0), None);
+    }
+
+    #[test]
+    fn path_to_nearest_stops_next_to_obstruction() {
+        let mut grid = Grid::default();
+        wall_at(&mut grid, (5, 0));
+        wall_at(&mut grid, (0, 0));
+
+        let path = grid
+            .find_path_to_nearest(&tile(&grid, (0, 0)), &tile(&grid, (5, 0)))
+            .unwrap();
+
+        let first = array_to_tuple(grid.world_to_coord(path.steps[0]));
+        let last = array_to_tuple(grid.world_to_coord(*path.steps.last().unwrap()));
+        assert!(!grid.is_obstructed(first));
+        assert!(!grid.is_obstructed(last));
+        assert!(ring((5, 0), 1).contains(&last));
+    }
+
+    #[test]
+    fn partial_path_when_budget_runs_out() {
+        let grid = Grid::default();
+        let mut request = PathRequest::new(tile(&grid, (0, 0)), tile(&grid, (40, 0)));
+        request.budget.max_path_length = Some(10);
+
+        let path = grid.search(&request).result.unwrap();
+        assert!(path.partial);
+        assert_eq!(path.steps.len(), 11);
+        assert_eq!(grid.world_to_coord(*path.steps.last().unwrap()), [10, 0]);
+
+        request.allow_partial = false;
+        assert_eq!(
+            grid.search(&request).result.unwrap_err(),
+            PathfindingError::BudgetExhausted {
+                start: (0, 0),
+                goal: (40, 0)
+            }
+        );
+    }
+
+    #[test]
+    fn search_counts_expansions() {
+        let grid = Grid::default();
+        let request = PathRequest::new(tile(&grid, (0, 0)), tile(&grid, (5, 0)));
+
+        let search = grid.search(&request);
+        assert_eq!(search.result.unwrap().steps.len(), 6);
+        assert_eq!(search.expansions, 5);
+    }
+
+    #[test]
+    fn segment_tiles_follow_the_line() {
+        let grid = Grid::default();
+
+        assert_eq!(
+            grid.tiles_on_segment(tile(&grid, (0, 0)), tile(&grid, (3, 0))),
+            vec![(0, 0), (1, 0), (2, 0), (3, 0)]
+        );
+        // a diagonal passes by the corners, so the tiles beside them are included
+        assert_eq!(
+            grid.tiles_on_segment(tile(&grid, (0, 0)), tile(&grid, (1, 1))),
+            vec![(0, 0), (1, 0), (0, 1), (1, 1)]
+        );
+        assert_eq!(
+            grid.tiles_on_segment(tile(&grid, (0, 0)), tile(&grid, (-2, 1))),
+            vec![(0, 0), (-1, 0), (-1, 1), (-2, 1)]
+        );
+    }
+
+    #[test]
+    fn straight_corridor_collapses_to_two_waypoints() {
+        let mut grid = Grid::default();
+        for x in -1..=21 {
+            wall_at(&mut grid, (x, -1));
+            wall_at(&mut grid, (x, 1));
+        }
+
+        let mut path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (20, 0)))
+            .unwrap();
+        assert_eq!(path.steps.len(), 21);
+
+        path.smooth(&grid);
+        assert_eq!(path.steps, vec![tile(&grid, (0, 0)), tile(&grid, (20, 0))]);
+    }
+
+    #[test]
+    fn open_floor_collapses_to_two_waypoints() {
+        let grid = Grid::default();
+
+        let mut path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (12, 5)))
+            .unwrap();
+        path.smooth(&grid);
+
+        assert_eq!(path.steps, vec![tile(&grid, (0, 0)), tile(&grid, (12, 5))]);
+    }
+
+    #[test]
+    fn smoothing_keeps_the_turn_around_a_wall() {
+        let mut grid = Grid::default();
+        for z in -10..=3 {
+            wall_at(&mut grid, (5, z));
+        }
+
+        let mut path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (10, 0)))
+            .unwrap();
+        path.smooth(&grid);
+
+        assert!(path.steps.len() > 2);
+        assert_eq!(path.steps[0], tile(&grid, (0, 0)));
+        assert_eq!(*path.steps.last().unwrap(), tile(&grid, (10, 0)));
+        assert!(path
+            .steps
+            .windows(2)
+            .all(|pair| grid.line_of_sight(pair[0], pair[1])));
+    }
+
+    #[test]
+    fn corner_cutting_when_allowed() {
+        let mut grid = Grid {
+            diagonal_movement: DiagonalMovement::Always,
+            ..Default::default()
+        };
+        wall_at(&mut grid, (1, 0));
+        wall_at(&mut grid, (0, 1));
+
+        let path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (1, 1)))
+            .unwrap();
+
+        assert_eq!(path.steps.len(), 2);
+    }
+
+    #[test]
+    fn no_diagonal_steps_when_never() {
+        let mut grid = Grid {
+            diagonal_movement: DiagonalMovement::Never,
+            ..Default::default()
+        };
+
+        let path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (2, 2)))
+            .unwrap();
+
+        assert_eq!(path.steps.len(), 5);
+    }
+
+    #[test]
+    fn snapshots_keep_their_tiles_after_the_grid_changes() {
+        let mut grid = Grid::default();
+        grid.set_obstructed((1, 1), true);
+        let snapshot = grid.snapshot();
+
+        grid.set_obstructed((1, 1), false);
+        grid.set_obstructed((2, 2), true);
+
+        assert!(snapshot.is_obstructed((1, 1)));
+        assert!(!snapshot.is_obstructed((2, 2)));
+        assert!(!grid.is_obstructed((1, 1)));
+        assert_ne!(snapshot.version(), grid.version());
+    }
+
+    #[test]
+    fn snapshots_are_reused_until_the_grid_changes() {
+        let mut grid = Grid::default();
+        let graph = HierarchicalGraph::default();
+        let mut snapshots = GridSnapshot::default();
+
+        let (first, _) = snapshots.get(&grid, &graph);
+        let (second, _) = snapshots.get(&grid, &graph);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        grid.set_obstructed((1, 1), true);
+        let (third, _) = snapshots.get(&grid, &graph);
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert!(third.is_obstructed((1, 1)));
+    }
+
+    #[test]
+    fn reserved_tiles_belong_to_one_entity() {
+        let mut grid = Grid::default();
+        let (a, b) = (Entity::from_raw(0), Entity::from_raw(1));
+
+        assert!(grid.reserve((1, 1), a));
+        assert!(!grid.reserve((1, 1), b));
+        assert!(grid.reserve((2, 1), a));
+        assert_eq!(grid.occupant((1, 1)), None);
+        assert_eq!(grid.reserved_tile(a), Some((2, 1)));
+
+        grid.release(a);
+        assert!(grid.reserve((2, 1), b));
+    }
+
+    #[test]
+    fn paths_stop_next_to_a_reserved_goal() {
+        let mut grid = Grid::default();
+        let (a, b) = (Entity::from_raw(0), Entity::from_raw(1));
+        grid.reserve((5, 0), a);
+
+        let request = PathRequest {
+            entity: Some(b),
+            ..PathRequest::new(tile(&grid, (0, 0)), tile(&grid, (5, 0)))
+        };
+        let (_, goal) = grid.request_tiles(&request).unwrap();
+        assert_ne!(goal, (5, 0));
+        assert_eq!(
+            grid.cost_model.heuristic(goal, (5, 0)),
+            grid.cost_model.straight
+        );
+
+        let request = PathRequest {
+            entity: Some(a),
+            ..request
+        };
+        assert_eq!(grid.request_tiles(&request).unwrap().1, (5, 0));
+    }
+
+    fn path_tiles(grid: &Grid, path: &Path) -> Vec<(i32, i32)> {
+        path.steps
+            .iter()
+            .map(|&step| array_to_tuple(grid.world_to_coord(step)))
+            .collect()
+    }
+
+    /// Sum of the step costs of a path that walks tile by tile
+    fn path_cost(grid: &Grid, path: &Path) -> u32 {
+        path_tiles(grid, path)
+            .windows(2)
+            .map(|pair| {
+                let diagonal = pair[0].0 != pair[1].0 && pair[0].1 != pair[1].1;
+                grid.cost_model
+                    .step_cost(&grid.get_tile(pair[1]).unwrap(), diagonal)
+            })
+            .sum()
+    }
+
+    #[test]
+    fn steps_are_weighted_by_floor_and_occupant() {
+        let mut grid = Grid::default();
+        grid.set_floor((1, 0), FloorType::Carpet);
+        grid.reserve((2, 0), Entity::from_raw(0));
+        let model = &grid.cost_model;
+
+        assert_eq!(model.step_cost(&grid.get_tile((0, 0)).unwrap(), false), 10);
+        assert_eq!(model.step_cost(&grid.get_tile((0, 0)).unwrap(), true), 14);
+        assert_eq!(model.step_cost(&grid.get_tile((1, 0)).unwrap(), false), 20);
+        assert_eq!(model.step_cost(&grid.get_tile((2, 0)).unwrap(), true), 70);
+    }
+
+    #[test]
+    fn paths_walk_around_carpet_and_occupied_tiles() {
+        let mut grid = Grid::default();
+        grid.set_floor((3, 0), FloorType::Carpet);
+        grid.reserve((3, 4), Entity::from_raw(0));
+
+        // stepping around the tile costs 68 and crossing it 70
+        let path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (6, 0)))
+            .unwrap();
+        assert!(!path_tiles(&grid, &path).contains(&(3, 0)));
+        assert_eq!(path_cost(&grid, &path), 68);
+
+        let path = grid
+            .find_path(&tile(&grid, (0, 4)), &tile(&grid, (6, 4)))
+            .unwrap();
+        assert!(!path_tiles(&grid, &path).contains(&(3, 4)));
+    }
+
+    #[test]
+    fn paths_cross_carpet_when_the_detour_costs_more() {
+        let mut grid = Grid::default();
+        for z in -10..=10 {
+            grid.set_floor((3, z), FloorType::Carpet);
+        }
+
+        let path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (6, 0)))
+            .unwrap();
+        assert!(path_tiles(&grid, &path).contains(&(3, 0)));
+        assert_eq!(path_cost(&grid, &path), 70);
+    }
+
+    #[test]
+    fn heuristic_never_overestimates() {
+        let mut grid = Grid::default();
+        for (x, z) in [(1, 0), (2, 2), (-3, 1), (0, -2)] {
+            grid.set_floor((x, z), FloorType::Carpet);
+        }
+        grid.reserve((1, 1), Entity::from_raw(0));
+
+        for cost_model in [
+            CostModel::default(),
+            // diagonals more expensive than two straight steps
+            CostModel {
+                diagonal: 30,
+                ..Default::default()
+            },
+        ] {
+            grid.cost_model = cost_model;
+            for (x, z) in [(0, 0), (1, 1), (-2, 3)] {
+                for (neighbor, cost) in grid.walkable_neighbors((x, z)) {
+                    assert!(grid.cost_model.heuristic((x, z), neighbor) <= cost);
+                }
+            }
+            for goal in [(5, 3), (-4, -4), (2, 2), (0, 7)] {
+                let path = grid
+                    .find_path(&tile(&grid, (0, 0)), &tile(&grid, goal))
+                    .unwrap();
+                assert!(grid.cost_model.heuristic((0, 0), goal) <= path_cost(&grid, &path));
+            }
+        }
+    }
+
+    #[test]
+    fn smoothing_keeps_going_around_carpet() {
+        let mut grid = Grid::default();
+        for x in 4..=8 {
+            for z in -1..=1 {
+                grid.set_floor((x, z), FloorType::Carpet);
+            }
+        }
+
+        let mut path = grid
+            .find_path(&tile(&grid, (0, 0)), &tile(&grid, (12, 0)))
+            .unwrap();
+        path.smooth(&grid);
+
+        assert!(path.steps.len() > 2);
+        assert!(path.steps.windows(2).all(|pair| grid
+            .tiles_on_segment(pair[0], pair[1])
+            .iter()
+            .all(|&coord| grid.floor(coord) != Some(FloorType::Carpet))));
+    }
+
+    #[test]
+    fn smoothing_looks_ahead_a_limited_number_of_steps() {
+        let grid = Grid::default();
+
+        let mut path = grid
+            .find_path(&tile(&grid, (-40, 0)), &tile(&grid, (40, 0)))
+            .unwrap();
+        path.smooth(&grid);
+
+        assert_eq!(
+            path.steps,
+            vec![
+                tile(&grid, (-40, 0)),
+                tile(&grid, (-8, 0)),
+                tile(&grid, (24, 0)),
+                tile(&grid, (40, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_chunks_are_unloaded() {
+        let mut grid = Grid::new(0.2, 4, GridBounds::new((-8, -8), (8, 8)));
+        grid.set_obstructed((1, 1), true);
+        grid.set_floor((2, 1), FloorType::Carpet);
+        grid.set_obstructed((-1, -1), true);
+        assert_eq!(grid.loaded_chunks().count(), 2);
+
+        // the chunk stays while one of its tiles is still used
+        grid.set_obstructed((1, 1), false);
+        assert_eq!(grid.loaded_chunks().count(), 2);
+        grid.set_floor((2, 1), FloorType::None);
+        assert_eq!(grid.loaded_chunks().collect::<Vec<_>>(), vec![&(-1, -1)]);
+
+        grid.set_obstructed((-1, -1), false);
+        assert_eq!(grid.loaded_chunks().count(), 0);
+        assert_eq!(grid.stored_tiles().count(), 0);
+    }
+
+    #[test]
+    fn searches_stay_inside_the_lot() {
+        let mut grid = Grid::new(0.2, 4, GridBounds::new((0, 0), (10, 10)));
+        // a wall across the whole lot, the only way around is outside of it
+        for z in 0..10 {
+            wall_at(&mut grid, (5, z));
+        }
+        let error = grid
+            .find_path(&tile(&grid, (2, 5)), &tile(&grid, (8, 5)))
+            .unwrap_err();
+        assert!(matches!(error, PathfindingError::GoalUnreachable { .. }));
+
+        // with a gap at the edge the path goes through it, never past the bounds
+        grid.set_obstructed((5, 9), false);
+        let path = grid
+            .find_path(&tile(&grid, (2, 5)), &tile(&grid, (8, 5)))
+            .unwrap();
+        assert!(path
+            .steps
+            .windows(2)
+            .flat_map(|pair| grid.tiles_on_segment(pair[0], pair[1]))
+            .all(|coord| grid.in_bounds(coord)));
+        assert!(path_tiles(&grid, &path).contains(&(5, 9)));
+
+        // nearest tiles are searched inside the lot as well
+        let outside = grid.coord_to_tile([-3, 4]);
+        assert_eq!(grid.nearest_free_tile(outside, 5), Some((0, 4)));
+    }
+
+    #[test]
+    fn bulk_queries_are_clamped_to_the_bounds() {
+        let mut grid = Grid::new(0.2, 4, GridBounds::new((0, 0), (10, 10)));
+
+        let tiles: Vec<(i32, i32)> = grid
+            .tiles_in_area((-5, -5), (1, 1))
+            .map(|(coord, _)| coord)
+            .collect();
+        assert_eq!(tiles, vec![(0, 0), (1, 0), (0, 1), (1, 1)]);
+
+        grid.update_area((8, 8), (20, 20), |tile| tile.floor = FloorType::Wood);
+        let mut wood = grid.find_tiles(|tile| tile.floor == FloorType::Wood);
+        wood.sort();
+        assert_eq!(wood, vec![(8, 8), (8, 9), (9, 8), (9, 9)]);
+        assert!(grid.floor((10, 10)).is_none());
+    }
+}
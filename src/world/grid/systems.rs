@@ -1,22 +1,64 @@
-use crate::world::grid::resources::Grid;
+use crate::world::grid::{events::GridChanged, hierarchical::HierarchicalGraph, resources::Grid};
 use bevy::prelude::*;
 
+/// Draw the playable bounds and the tiles of every loaded chunk
 pub fn show_grid(grid: Res<Grid>, mut gizmos: Gizmos) {
-    for i in 0..50 {
-        for t in 0..50 {
+    let rotation = Quat::from_rotation_x(90.0_f32.to_radians());
+
+    let min = Vec3::new(grid.bounds.min.0 as f32, 0.0, grid.bounds.min.1 as f32) * grid.tile_size;
+    let max = Vec3::new(grid.bounds.max.0 as f32, 0.0, grid.bounds.max.1 as f32) * grid.tile_size;
+    gizmos.rect(
+        (min + max) / 2.0,
+        rotation,
+        Vec2::new(max.x - min.x, max.z - min.z),
+        Color::WHITE,
+    );
+
+    for &chunk in grid.loaded_chunks() {
+        for (i, t) in grid.chunk_tiles(chunk) {
             let center_world_position = grid.coord_to_tile([i, t]);
 
-            let tile_color = match grid.get_tile_status(i, t) {
-                Some(true) => Color::RED,
-                Some(false) | None => Color::GREEN,
+            let tile_color = match grid.get_tile((i, t)) {
+                Some(tile) if tile.obstructed => Color::RED,
+                Some(_) => Color::GREEN,
+                None => continue,
             };
 
             gizmos.rect(
                 center_world_position,
-                Quat::from_rotation_x(90.0_f32.to_radians()),
+                rotation,
                 Vec2::new(grid.tile_size - 0.02, grid.tile_size - 0.02),
                 tile_color,
             )
         }
     }
 }
+
+/// Send the tiles changed by the grid mutations of this frame as a [GridChanged] event
+pub fn emit_grid_changes(mut grid: ResMut<Grid>, mut events: EventWriter<GridChanged>) {
+    let changes = grid.bypass_change_detection().take_changed_tiles();
+    if !changes.is_empty() {
+        events.send(GridChanged {
+            tiles: changes.tiles,
+            obstructed: changes.obstructed,
+            doors: changes.doors,
+        });
+    }
+}
+
+/// Keep the hierarchical graph in sync with the obstruction and step costs of the grid
+pub fn update_hierarchical_graph(
+    grid: Res<Grid>,
+    mut graph: ResMut<HierarchicalGraph>,
+    mut events: EventReader<GridChanged>,
+) {
+    if !graph.is_built() {
+        *graph = HierarchicalGraph::build(&grid);
+        events.clear();
+        return;
+    }
+
+    for event in events.iter() {
+        graph.repair(&grid, event.tiles.iter().copied());
+    }
+}
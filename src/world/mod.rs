@@ -7,11 +7,19 @@ use grid::*;
 pub mod physics;
 use physics::*;
 
+pub mod rooms;
+use rooms::*;
+
+pub mod furniture;
+use furniture::*;
+
 impl Plugin for WorldPlugin {
     fn build(&self, app: &mut App) {
         //systems
         //plugins
         app.add_plugins(GridPlugin);
         app.add_plugins(PhysicsPlugin);
+        app.add_plugins(RoomsPlugin);
+        app.add_plugins(FurniturePlugin);
     }
 }
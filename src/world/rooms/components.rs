@@ -0,0 +1,5 @@
+use bevy::prelude::*;
+
+/// Component to attach to the buildings NPCs use to walk in and out of a room
+#[derive(Component)]
+pub struct Door {}
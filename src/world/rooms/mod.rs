@@ -0,0 +1,31 @@
+//! Detects the rooms enclosed by the buildings.
+//!
+//! A room is a group of free tiles of the grid surrounded by obstructed tiles and doors without reaching the lot bounds.
+use bevy::prelude::*;
+
+pub mod systems;
+use systems::*;
+pub mod components;
+use components::*;
+pub mod resources;
+use resources::*;
+pub mod states;
+use states::*;
+
+use super::grid::systems::emit_grid_changes;
+
+pub struct RoomsPlugin;
+
+impl Plugin for RoomsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Rooms>();
+        app.add_systems(
+            Update,
+            (
+                update_rooms.after(emit_grid_changes),
+                validate_rooms.run_if(room_contents_changed),
+            )
+                .chain(),
+        );
+    }
+}
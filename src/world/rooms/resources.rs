@@ -0,0 +1,480 @@
+use bevy::prelude::*;
+use hashbrown::{HashMap, HashSet};
+use std::fmt;
+
+use crate::{
+    npc::components::Need,
+    world::{
+        furniture::components::FurnitureKind, grid::resources::Grid,
+        physics::components::BoxCollider,
+    },
+};
+
+/// A group of free tiles enclosed by obstructed tiles and doors.
+#[derive(Clone, Debug)]
+pub struct Room {
+    pub id: u32,
+    pub tiles: HashSet<(i32, i32)>,
+    /// Floor area in world units
+    pub area: f32,
+    /// Use the player designated for the room
+    pub room_type: Option<RoomType>,
+    /// Why the room does not meet the requirements of its type, empty for valid rooms
+    pub issues: Vec<RoomIssue>,
+}
+
+impl Room {
+    /// Return if the room has a type and meets all its requirements
+    pub fn is_valid(&self) -> bool {
+        self.room_type.is_some() && self.issues.is_empty()
+    }
+
+    /// Return if the room is valid and its type satisfies the need
+    pub fn satisfies(&self, need: Need) -> bool {
+        self.is_valid()
+            && self
+                .room_type
+                .is_some_and(|room_type| room_type.satisfied_needs().contains(&need))
+    }
+}
+
+/// Uses the player can designate for a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RoomType {
+    OpenOffice,
+    MeetingRoom,
+    Kitchen,
+    Restroom,
+    ServerRoom,
+    Lounge,
+}
+
+/// What a room needs to be used as its type.
+#[derive(Clone, Copy, Debug)]
+pub struct RoomRequirements {
+    /// Minimum floor area in world units
+    pub min_area: f32,
+    pub furniture: &'static [FurnitureKind],
+    pub door: bool,
+}
+
+impl RoomType {
+    pub const ALL: [RoomType; 6] = [
+        RoomType::OpenOffice,
+        RoomType::MeetingRoom,
+        RoomType::Kitchen,
+        RoomType::Restroom,
+        RoomType::ServerRoom,
+        RoomType::Lounge,
+    ];
+
+    pub fn requirements(&self) -> RoomRequirements {
+        match self {
+            RoomType::OpenOffice => RoomRequirements {
+                min_area: 4.0,
+                furniture: &[FurnitureKind::Desk, FurnitureKind::Chair],
+                door: true,
+            },
+            RoomType::MeetingRoom => RoomRequirements {
+                min_area: 3.0,
+                furniture: &[FurnitureKind::Whiteboard, FurnitureKind::Chair],
+                door: true,
+            },
+            RoomType::Kitchen => RoomRequirements {
+                min_area: 2.0,
+                furniture: &[FurnitureKind::CoffeeMachine],
+                door: true,
+            },
+            RoomType::Restroom => RoomRequirements {
+                min_area: 1.0,
+                furniture: &[],
+                door: true,
+            },
+            RoomType::ServerRoom => RoomRequirements {
+                min_area: 1.0,
+                furniture: &[FurnitureKind::Server],
+                door: true,
+            },
+            RoomType::Lounge => RoomRequirements {
+                min_area: 3.0,
+                furniture: &[FurnitureKind::Chair, FurnitureKind::Plant],
+                door: true,
+            },
+        }
+    }
+
+    /// Needs of the NPCs that a valid room of this type satisfies
+    pub fn satisfied_needs(&self) -> &'static [Need] {
+        match self {
+            RoomType::Kitchen => &[Need::Hungry, Need::Thirst],
+            RoomType::Restroom => &[Need::Relief],
+            RoomType::Lounge => &[Need::Energy],
+            RoomType::OpenOffice | RoomType::MeetingRoom | RoomType::ServerRoom => &[],
+        }
+    }
+
+    /// Return every requirement a room with the given area, furniture and doors does not meet
+    pub fn validate(
+        &self,
+        area: f32,
+        furniture: &HashMap<FurnitureKind, usize>,
+        has_door: bool,
+    ) -> Vec<RoomIssue> {
+        let requirements = self.requirements();
+        let mut issues = Vec::new();
+
+        if area < requirements.min_area {
+            issues.push(RoomIssue::TooSmall {
+                area,
+                min_area: requirements.min_area,
+            });
+        }
+        for &kind in requirements.furniture {
+            if furniture.get(&kind).copied().unwrap_or(0) == 0 {
+                issues.push(RoomIssue::MissingFurniture(kind));
+            }
+        }
+        if requirements.door && !has_door {
+            issues.push(RoomIssue::NoDoor);
+        }
+
+        issues
+    }
+}
+
+/// A requirement of its type that a room does not meet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoomIssue {
+    TooSmall { area: f32, min_area: f32 },
+    MissingFurniture(FurnitureKind),
+    NoDoor,
+}
+
+impl fmt::Display for RoomIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomIssue::TooSmall { area, min_area } => {
+                write!(f, "the room is too small ({area:.1} of {min_area:.1})")
+            }
+            RoomIssue::MissingFurniture(kind) => write!(f, "the room needs a {kind:?}"),
+            RoomIssue::NoDoor => write!(f, "the room has no door"),
+        }
+    }
+}
+
+/// Every room detected in the grid, the room of each tile is also stored in the tile itself.
+#[derive(Resource, Default)]
+pub struct Rooms {
+    rooms: HashMap<u32, Room>,
+    next_id: u32,
+}
+
+impl Rooms {
+    pub fn get(&self, id: u32) -> Option<&Room> {
+        self.rooms.get(&id)
+    }
+
+    /// Return the room that holds the tile
+    pub fn room_at(&self, grid: &Grid, coord: (i32, i32)) -> Option<&Room> {
+        grid.room(coord).and_then(|id| self.get(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Room> {
+        self.rooms.values()
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Room> {
+        self.rooms.get_mut(&id)
+    }
+
+    /// Set the use of a room, returns false when the room does not exist
+    pub fn designate(&mut self, id: u32, room_type: Option<RoomType>) -> bool {
+        match self.rooms.get_mut(&id) {
+            Some(room) => {
+                room.room_type = room_type;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Return the valid rooms that satisfy a need
+    pub fn satisfying(&self, need: Need) -> impl Iterator<Item = &Room> {
+        self.rooms.values().filter(move |room| room.satisfies(need))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    /// Detect every room of the grid from scratch
+    pub fn detect(&mut self, grid: &mut Grid) {
+        let seeds: Vec<(i32, i32)> = grid
+            .tiles_in_area(grid.bounds.min, grid.bounds.max)
+            .map(|(coord, _)| coord)
+            .collect();
+        self.redetect(grid, seeds, self.rooms.keys().copied().collect());
+    }
+
+    /// Detect again only the rooms next to the changed tiles.
+    ///
+    /// Rooms keep their id when most of their tiles are still in the same room, so a room split by a
+    /// new wall keeps its id in the bigger part.
+    pub fn update(&mut self, grid: &mut Grid, changed: &HashSet<(i32, i32)>) {
+        let mut seeds: HashSet<(i32, i32)> = HashSet::new();
+        for &coord in changed {
+            seeds.insert(coord);
+            seeds.extend(neighbors(coord));
+        }
+
+        let affected: Vec<u32> = seeds
+            .iter()
+            .filter_map(|&coord| grid.room(coord))
+            .collect::<HashSet<u32>>()
+            .into_iter()
+            .collect();
+        for id in &affected {
+            seeds.extend(self.rooms[id].tiles.iter().copied());
+        }
+
+        self.redetect(grid, seeds.into_iter().collect(), affected);
+    }
+
+    /// Replace the `old` rooms with the rooms flood filled from the seeds
+    fn redetect(&mut self, grid: &mut Grid, seeds: Vec<(i32, i32)>, old: Vec<u32>) {
+        let old_rooms: Vec<Room> = old.iter().filter_map(|id| self.rooms.remove(id)).collect();
+        for room in &old_rooms {
+            for &coord in &room.tiles {
+                grid.set_room(coord, None);
+            }
+        }
+
+        let mut visited = HashSet::new();
+        let mut regions: Vec<HashSet<(i32, i32)>> = seeds
+            .into_iter()
+            .filter_map(|seed| flood_fill(grid, seed, &mut visited))
+            .collect();
+        // bigger regions choose their id first
+        regions.sort_by_key(|region| std::cmp::Reverse(region.len()));
+
+        let mut reused = HashSet::new();
+        for tiles in regions {
+            let id = old_rooms
+                .iter()
+                .filter(|room| !reused.contains(&room.id))
+                .map(|room| (room.tiles.intersection(&tiles).count(), room))
+                .filter(|(overlap, room)| overlap * 2 > room.tiles.len())
+                .max_by_key(|(overlap, _)| *overlap)
+                .map(|(_, room)| room.id)
+                .unwrap_or_else(|| {
+                    self.next_id += 1;
+                    self.next_id
+                });
+            reused.insert(id);
+
+            for &coord in &tiles {
+                grid.set_room(coord, Some(id));
+            }
+            let area = tiles.len() as f32 * grid.tile_size * grid.tile_size;
+            // a room keeps its type while it keeps its id, the issues are checked again later
+            let (room_type, issues) = old_rooms
+                .iter()
+                .find(|room| room.id == id)
+                .map(|room| (room.room_type, room.issues.clone()))
+                .unwrap_or_default();
+            self.rooms.insert(
+                id,
+                Room {
+                    id,
+                    tiles,
+                    area,
+                    room_type,
+                    issues,
+                },
+            );
+        }
+    }
+}
+
+/// Return the rooms next to a group of tiles, like the tiles of a door or a desk
+pub fn rooms_around(grid: &Grid, tiles: &[(i32, i32)]) -> HashSet<u32> {
+    tiles
+        .iter()
+        .flat_map(|&coord| std::iter::once(coord).chain(neighbors(coord)))
+        .filter_map(|coord| grid.room(coord))
+        .collect()
+}
+
+/// Count the pieces of furniture of each kind in each room, a piece against a wall counts in the rooms at both sides
+pub fn furniture_in_rooms<'a>(
+    grid: &Grid,
+    pieces: impl Iterator<Item = (FurnitureKind, &'a BoxCollider)>,
+) -> HashMap<u32, HashMap<FurnitureKind, usize>> {
+    let mut furniture: HashMap<u32, HashMap<FurnitureKind, usize>> = HashMap::new();
+    for (kind, collider) in pieces {
+        for room in rooms_around(grid, &grid.tiles_covered_by_collider(collider)) {
+            *furniture.entry(room).or_default().entry(kind).or_default() += 1;
+        }
+    }
+    furniture
+}
+
+/// Return the free tiles connected to the seed, or None when they reach the bounds of the grid
+fn flood_fill(
+    grid: &Grid,
+    seed: (i32, i32),
+    visited: &mut HashSet<(i32, i32)>,
+) -> Option<HashSet<(i32, i32)>> {
+    if is_boundary(grid, seed) || !visited.insert(seed) {
+        return None;
+    }
+
+    let mut tiles = HashSet::from([seed]);
+    let mut open = vec![seed];
+    let mut enclosed = true;
+    while let Some(coord) = open.pop() {
+        for neighbor in neighbors(coord) {
+            if !grid.in_bounds(neighbor) {
+                enclosed = false;
+            }
+            if is_boundary(grid, neighbor) || !visited.insert(neighbor) {
+                continue;
+            }
+            tiles.insert(neighbor);
+            open.push(neighbor);
+        }
+    }
+
+    enclosed.then_some(tiles)
+}
+
+/// Walls and doors bound the rooms, NPCs walk through the doors but they do not join the rooms
+fn is_boundary(grid: &Grid, coord: (i32, i32)) -> bool {
+    grid.is_obstructed(coord) || grid.is_door(coord)
+}
+
+/// Rooms are connected only by straight steps, so a diagonal gap between two walls does not join them
+fn neighbors(coord: (i32, i32)) -> [(i32, i32); 4] {
+    [
+        (coord.0 + 1, coord.1),
+        (coord.0 - 1, coord.1),
+        (coord.0, coord.1 + 1),
+        (coord.0, coord.1 - 1),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Obstruct the border of the rectangle between `min` and `max` ( both inclusive )
+    fn walls(grid: &mut Grid, min: (i32, i32), max: (i32, i32)) {
+        for x in min.0..=max.0 {
+            grid.set_obstructed((x, min.1), true);
+            grid.set_obstructed((x, max.1), true);
+        }
+        for z in min.1..=max.1 {
+            grid.set_obstructed((min.0, z), true);
+            grid.set_obstructed((max.0, z), true);
+        }
+    }
+
+    #[test]
+    fn walls_enclose_a_room() {
+        let mut grid = Grid::default();
+        let mut rooms = Rooms::default();
+        walls(&mut grid, (0, 0), (4, 3));
+        rooms.detect(&mut grid);
+
+        assert_eq!(rooms.len(), 1);
+        let room = rooms.room_at(&grid, (1, 1)).unwrap();
+        assert_eq!(room.tiles.len(), 6);
+        assert!((room.area - 6.0 * 0.04).abs() < 1e-6);
+        assert_eq!(grid.room((0, 0)), None);
+        assert_eq!(grid.room((5, 5)), None);
+    }
+
+    #[test]
+    fn rooms_follow_the_walls() {
+        let mut grid = Grid::default();
+        let mut rooms = Rooms::default();
+        walls(&mut grid, (0, 0), (6, 3));
+        rooms.detect(&mut grid);
+        let id = grid.room((1, 1)).unwrap();
+
+        // splitting the room keeps the id in the bigger part
+        grid.take_changed_tiles();
+        for z in 0..=3 {
+            grid.set_obstructed((2, z), true);
+        }
+        let changed = grid.take_changed_tiles().obstructed;
+        rooms.update(&mut grid, &changed);
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(grid.room((4, 1)), Some(id));
+        assert_ne!(grid.room((1, 1)), Some(id));
+        assert!(grid.room((1, 1)).is_some());
+
+        // opening the outer wall turns the small room into outside
+        grid.set_obstructed((0, 1), false);
+        let changed = grid.take_changed_tiles().obstructed;
+        rooms.update(&mut grid, &changed);
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(grid.room((1, 1)), None);
+        assert_eq!(grid.room((4, 1)), Some(id));
+    }
+
+    #[test]
+    fn paths_enter_closed_rooms_through_their_doors() {
+        let mut grid = Grid::default();
+        let mut rooms = Rooms::default();
+        walls(&mut grid, (2, 2), (6, 5));
+        rooms.detect(&mut grid);
+        let outside = grid.coord_to_tile([4, 0]);
+        let inside = grid.coord_to_tile([4, 3]);
+        assert!(grid.find_path(&outside, &inside).is_err());
+
+        // the door replaces a piece of wall
+        grid.take_changed_tiles();
+        grid.set_obstructed((4, 2), false);
+        let door = BoxCollider::new(
+            grid.coord_to_tile([4, 2]),
+            Quat::IDENTITY,
+            Vec3::splat(grid.tile_size * 0.5),
+        );
+        grid.mark_door_from_collider(&door);
+        let changes = grid.take_changed_tiles();
+        let changed = changes.obstructed.union(&changes.doors).copied().collect();
+        rooms.update(&mut grid, &changed);
+
+        assert_eq!(rooms.len(), 1);
+        assert!(grid.is_door((4, 2)));
+        assert_eq!(grid.room((4, 2)), None);
+        assert!(grid.room((4, 3)).is_some());
+        let path = grid.find_path(&outside, &inside).unwrap();
+        assert!(!path.partial);
+        assert_eq!(grid.world_to_coord(*path.steps.last().unwrap()), [4, 3]);
+    }
+
+    #[test]
+    fn room_types_list_missing_requirements() {
+        let furniture = HashMap::from([(FurnitureKind::CoffeeMachine, 1)]);
+
+        assert!(RoomType::Kitchen.validate(2.0, &furniture, true).is_empty());
+        assert_eq!(
+            RoomType::Lounge.validate(1.0, &furniture, false),
+            vec![
+                RoomIssue::TooSmall {
+                    area: 1.0,
+                    min_area: 3.0
+                },
+                RoomIssue::MissingFurniture(FurnitureKind::Chair),
+                RoomIssue::MissingFurniture(FurnitureKind::Plant),
+                RoomIssue::NoDoor,
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use bevy::prelude::*;
+use hashbrown::{HashMap, HashSet};
+
+use crate::world::{
+    furniture::components::{Furniture, FurnitureKind},
+    grid::{events::GridChanged, resources::Grid},
+    physics::components::BoxCollider,
+};
+
+use super::{
+    components::Door,
+    resources::{furniture_in_rooms, rooms_around, Rooms},
+};
+
+/// Detect again the rooms around the tiles changed by placing or destroying walls and doors
+pub fn update_rooms(
+    mut grid: ResMut<Grid>,
+    mut rooms: ResMut<Rooms>,
+    mut events: EventReader<GridChanged>,
+) {
+    let changed: HashSet<(i32, i32)> = events
+        .iter()
+        .flat_map(|event| event.obstructed.iter().chain(&event.doors).copied())
+        .collect();
+    if changed.is_empty() {
+        return;
+    }
+
+    rooms.update(&mut grid, &changed);
+}
+
+/// Run condition of [validate_rooms], true when the rooms or the furniture and doors in them changed
+pub fn room_contents_changed(
+    rooms: Res<Rooms>,
+    moved: Query<(), Changed<BoxCollider>>,
+    added_doors: Query<(), Added<Door>>,
+    added_furniture: Query<(), Added<Furniture>>,
+    mut removed_doors: RemovedComponents<Door>,
+    mut removed_furniture: RemovedComponents<Furniture>,
+) -> bool {
+    // read every removal so they do not trigger the validation again next frame
+    let removed = removed_doors.iter().count() + removed_furniture.iter().count() > 0;
+    removed
+        || rooms.is_changed()
+        || !moved.is_empty()
+        || !added_doors.is_empty()
+        || !added_furniture.is_empty()
+}
+
+/// Check the designated rooms against the requirements of their type and flag the ones not meeting them
+pub fn validate_rooms(
+    grid: Res<Grid>,
+    mut rooms: ResMut<Rooms>,
+    furniture_query: Query<(&Furniture, &BoxCollider)>,
+    door_query: Query<&BoxCollider, With<Door>>,
+) {
+    let furniture = furniture_in_rooms(
+        &grid,
+        furniture_query
+            .iter()
+            .map(|(piece, collider)| (piece.kind, collider)),
+    );
+    let doors: HashSet<u32> = door_query
+        .iter()
+        .flat_map(|collider| rooms_around(&grid, &grid.tiles_covered_by_collider(collider)))
+        .collect();
+
+    let no_furniture = HashMap::new();
+    let changes: Vec<(u32, Vec<_>)> = rooms
+        .iter()
+        .map(|room| {
+            let issues = room.room_type.map_or_else(Vec::new, |room_type| {
+                room_type.validate(
+                    room.area,
+                    furniture.get(&room.id).unwrap_or(&no_furniture),
+                    doors.contains(&room.id),
+                )
+            });
+            (room.id, issues)
+        })
+        .filter(|(id, issues)| rooms.get(*id).is_some_and(|room| room.issues != *issues))
+        .collect();
+
+    // only touch the rooms whose issues changed, so other systems can react to the change
+    for (id, issues) in changes {
+        let Some(room) = rooms.get_mut(id) else {
+            continue;
+        };
+        for issue in &issues {
+            warn!("Room {} is not a valid {:?}: {}", id, room.room_type, issue);
+        }
+        room.issues = issues;
+    }
+}